@@ -0,0 +1,190 @@
+//! Generators from the `Generators` table of the config.
+//! A generator is a command that writes C sources or headers
+//! into `build/gen/`. The generated sources are compiled with
+//! the project and the directory is on the include path, so
+//! nothing generated ends up in `src/`. A generator only runs
+//! again if one of its inputs is newer than its outputs
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context};
+use mlua::{Table, Value};
+
+use super::{config::string_list, scripts};
+
+/// Directory of the generated files, relative to the project
+pub const GEN_DIR: &str = "build/gen";
+
+/// Extension of the files in the generated directory
+/// that store the command a generator last ran with
+const STAMP_EXT: &str = "stamp";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Generator {
+    pub name: String,
+    /// Program and arguments, run in the project root
+    /// with `SURTUR_GEN_DIR` set to the generated directory
+    pub command: Vec<String>,
+    /// Files the generator reads, relative to the
+    /// project root. Patterns like `spec/*.json` are expanded
+    pub inputs: Vec<String>,
+    /// Files the generator writes, relative to the generated directory
+    pub outputs: Vec<PathBuf>,
+}
+
+impl Generator {
+    fn parse(name: &str, table: Table) -> anyhow::Result<Self> {
+        let mut generator = Self {
+            name: name.to_string(),
+            command: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        };
+        for pair in table.pairs::<String, Value>() {
+            let (key, val) = pair.context(format!("Invalid entry in generator `{}`", name))?;
+            match key.as_str() {
+                "command" => {
+                    generator.command = match val {
+                        Value::String(command) => command
+                            .to_str()?
+                            .split_whitespace()
+                            .map(String::from)
+                            .collect(),
+                        val => string_list("generator", name, &key, val)?,
+                    }
+                }
+                "inputs" => generator.inputs = string_list("generator", name, &key, val)?,
+                "outputs" => {
+                    generator.outputs = string_list("generator", name, &key, val)?
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect()
+                }
+                key => bail!("Invalid key in generator `{}`: {}", name, key),
+            }
+        }
+        if generator.command.is_empty() {
+            bail!("Generator `{}` has no `command`", name);
+        }
+        if generator.outputs.is_empty() {
+            bail!("Generator `{}` has no `outputs`", name);
+        }
+        Ok(generator)
+    }
+
+    /// Run the generator if its outputs are out of date
+    fn generate(&self, root_dir: &Path, gen_dir: &Path) -> anyhow::Result<()> {
+        let stamp_path = gen_dir.join(format!(".{}.{}", self.name, STAMP_EXT));
+        let stamp = self.command.join("\n");
+        let command_changed = !fs::read_to_string(&stamp_path).is_ok_and(|old| old == stamp);
+        if !command_changed && !self.is_stale(root_dir, gen_dir)? {
+            return Ok(());
+        }
+
+        let status = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .current_dir(root_dir)
+            .env("SURTUR_GEN_DIR", gen_dir)
+            .env("SURTUR_PROJ_DIR", root_dir)
+            .status()
+            .context(format!("Failed to run generator `{}`", self.name))?;
+        if !status.success() {
+            bail!(
+                "Generator `{}` failed with exit code: {}",
+                self.name,
+                status
+            );
+        }
+        for output in &self.outputs {
+            if !gen_dir.join(output).exists() {
+                bail!(
+                    "Generator `{}` didn't create its output: {}",
+                    self.name,
+                    output.display()
+                );
+            }
+        }
+        fs::write(&stamp_path, stamp).context("Failed to write generator stamp")
+    }
+
+    /// Whether an output is missing or an input is newer than the oldest output
+    fn is_stale(&self, root_dir: &Path, gen_dir: &Path) -> anyhow::Result<bool> {
+        let mut oldest_output: Option<SystemTime> = None;
+        for output in &self.outputs {
+            let Some(time) = modified(&gen_dir.join(output)) else {
+                return Ok(true);
+            };
+            oldest_output = Some(oldest_output.map_or(time, |oldest| oldest.min(time)));
+        }
+        for input in self.input_files(root_dir) {
+            let time = modified(&root_dir.join(&input)).context(format!(
+                "Input of generator `{}` doesn't exist: {}",
+                self.name, input
+            ))?;
+            if oldest_output.is_some_and(|oldest| time > oldest) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn input_files(&self, root_dir: &Path) -> Vec<String> {
+        self.inputs
+            .iter()
+            .flat_map(|input| match input.contains(['*', '?']) {
+                true => scripts::glob(root_dir, input),
+                false => vec![input.clone()],
+            })
+            .collect()
+    }
+}
+
+/// Run the generators that are out of date and return the
+/// generated sources, which are compiled with the project
+pub fn generate(root_dir: &Path, generators: &[Generator]) -> anyhow::Result<Vec<PathBuf>> {
+    if generators.is_empty() {
+        return Ok(Vec::new());
+    }
+    let gen_dir = root_dir.join(GEN_DIR);
+    fs::create_dir_all(&gen_dir).context("Failed to create generated directory")?;
+
+    let mut sources = Vec::new();
+    for generator in generators {
+        generator.generate(root_dir, &gen_dir)?;
+        sources.extend(
+            generator
+                .outputs
+                .iter()
+                .filter(|output| output.extension().is_some_and(|ext| ext == "c"))
+                .map(|output| gen_dir.join(output)),
+        );
+    }
+    Ok(sources)
+}
+
+/// Parse the `Generators` table, the generators run in order of their name
+pub fn parse_generators(table: Option<Table>) -> anyhow::Result<Vec<Generator>> {
+    let Some(table) = table else {
+        return Ok(Vec::new());
+    };
+    let mut generators = Vec::new();
+    for pair in table.pairs::<String, Value>() {
+        let (name, val) = pair.context("Invalid entry in `Generators`")?;
+        let Value::Table(table) = val else {
+            bail!("Generator `{}` has to be a table, found: {:?}", name, val);
+        };
+        generators.push(Generator::parse(&name, table)?);
+    }
+    generators.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(generators)
+}
+
+#[inline(always)]
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
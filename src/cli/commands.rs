@@ -0,0 +1,124 @@
+//! Custom commands from the `Commands` table of the config,
+//! run with `surtur x <name> [args]` or `surtur <name> [args]`.
+//! A command is a shell command, a list with a program and
+//! its arguments, or a lua function that gets the arguments
+//! and the same `surtur` table as build scripts
+
+use std::{fmt::Display, process::Command};
+
+use anyhow::{bail, Context};
+use mlua::{Function, Lua, RegistryKey, Table, Value};
+
+use super::{
+    sandbox,
+    scripts::{self, ScriptEnv},
+};
+
+pub enum Action {
+    /// Run with `sh -c`, the arguments are `$1`, `$2`, ...
+    Shell(String),
+    /// A program and its arguments, the arguments
+    /// of the command are appended
+    Program(Vec<String>),
+    /// A lua function of the config, stored in the registry of its lua state
+    Function(RegistryKey),
+}
+
+pub struct CustomCommand {
+    pub name: String,
+    /// Shown in `surtur --help`
+    pub description: Option<String>,
+    pub action: Action,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Shell(script) => f.write_str(script),
+            Action::Program(program) => f.write_str(&program.join(" ")),
+            Action::Function(_) => f.write_str("lua function"),
+        }
+    }
+}
+
+impl CustomCommand {
+    /// Run the command in the project root. `lua`
+    /// is the state the config was evaluated in
+    pub fn run(&self, lua: &Lua, env: &ScriptEnv, args: &[String]) -> anyhow::Result<()> {
+        let mut cmd = match &self.action {
+            Action::Function(key) => {
+                scripts::set_api(lua, env).context("Failed to create the `surtur` table")?;
+                let function: Function = lua.registry_value(key)?;
+                // Commands may run as long as they need to
+                return sandbox::unlimited(lua, || function.call::<_, ()>(args.to_vec()))
+                    .context(format!("Command `{}` failed", self.name));
+            }
+            Action::Shell(script) => {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c").arg(script).arg(&self.name);
+                cmd
+            }
+            Action::Program(program) => {
+                let mut cmd = Command::new(&program[0]);
+                cmd.args(&program[1..]);
+                cmd
+            }
+        };
+        let status = cmd
+            .args(args)
+            .current_dir(env.root_dir)
+            .env("SURTUR_PROJ_DIR", env.root_dir)
+            .env("SURTUR_PROJ_NAME", env.name)
+            .env("SURTUR_PROJ_VERSION", env.version)
+            .status()
+            .context(format!("Failed to run command `{}`", self.name))?;
+        if !status.success() {
+            bail!("Command `{}` failed with exit code: {}", self.name, status);
+        }
+        Ok(())
+    }
+}
+
+/// Parse the `Commands` table, the commands are sorted by name
+pub fn parse_commands(lua: &Lua, table: Option<Table>) -> anyhow::Result<Vec<CustomCommand>> {
+    let Some(table) = table else {
+        return Ok(Vec::new());
+    };
+    let mut commands = Vec::new();
+    for pair in table.pairs::<String, Value>() {
+        let (name, val) = pair.context("Invalid entry in `Commands`")?;
+        let mut description = None;
+        let action = match val {
+            Value::String(script) => Action::Shell(script.to_str()?.to_string()),
+            Value::Function(function) => Action::Function(lua.create_registry_value(function)?),
+            Value::Table(table) => {
+                description = table
+                    .get::<_, Option<String>>("description")
+                    .context(format!(
+                        "`description` of command `{}` has to be a string",
+                        name
+                    ))?;
+                let program: Vec<String> = table
+                    .sequence_values::<String>()
+                    .collect::<mlua::Result<_>>()
+                    .context(format!("Command `{}` has to be a list of strings", name))?;
+                if program.is_empty() {
+                    bail!("Command `{}` has no program", name);
+                }
+                Action::Program(program)
+            }
+            val => bail!(
+                "Command `{}` has to be a string, a list or a function, found: {:?}",
+                name,
+                val
+            ),
+        };
+        commands.push(CustomCommand {
+            name,
+            description,
+            action,
+        });
+    }
+    commands.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(commands)
+}
@@ -0,0 +1,55 @@
+//! Generation of the compilation database
+//! (compile_commands.json) that is used by
+//! editors and clangd to know how every
+//! source file is compiled
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::util;
+
+pub const COMPDB_FILE: &str = "compile_commands.json";
+
+/// A single translation unit in the compilation database
+pub struct Entry {
+    pub directory: PathBuf,
+    pub file: PathBuf,
+    pub output: PathBuf,
+    pub arguments: Vec<String>,
+}
+
+impl Entry {
+    fn to_json(&self) -> String {
+        let arguments: Vec<String> = self
+            .arguments
+            .iter()
+            .map(|arg| util::json_str(arg))
+            .collect();
+        format!(
+            concat!(
+                "  {{\n",
+                "    \"directory\": {},\n",
+                "    \"file\": {},\n",
+                "    \"output\": {},\n",
+                "    \"arguments\": [{}]\n",
+                "  }}"
+            ),
+            util::json_str(&self.directory.to_string_lossy()),
+            util::json_str(&self.file.to_string_lossy()),
+            util::json_str(&self.output.to_string_lossy()),
+            arguments.join(", ")
+        )
+    }
+}
+
+/// Write the compilation database to the root of the project
+pub fn write(root_dir: &Path, entries: &[Entry]) -> anyhow::Result<()> {
+    let entries: Vec<String> = entries.iter().map(Entry::to_json).collect();
+    let content = format!("[\n{}\n]\n", entries.join(",\n"));
+    fs::write(root_dir.join(COMPDB_FILE), content)
+        .context(format!("Failed to write {}", COMPDB_FILE))
+}
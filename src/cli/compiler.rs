@@ -1,23 +1,38 @@
-/// Handling of building and running the c program with gcc.
+/// Handling of building and running the c program.
 /// This inclues functions for
 /// building, running, linking and bundling libraries.
+pub mod toolchain;
+
 use std::{
     collections::HashSet,
+    ffi::OsString,
     fmt::Display,
     fs,
-    path::{Path, PathBuf},
+    io::{self, IsTerminal, Write},
+    path::{Component, Path, PathBuf},
     process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
 };
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 
-use crate::util;
+use crate::util::{self, depfile, jobs::JobPool};
 
 use super::{
-    config::{Config, ProjType},
-    deps::DepManager,
+    compdb,
+    config::{Bin, Config, LibKind, ProjType},
+    deps::{DepGraph, DepNode},
+    features,
+    profile::Profile,
+    target::Target,
 };
 
+use self::toolchain::Toolchain;
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub enum Standard {
     C89,
@@ -68,155 +83,664 @@ pub enum CompType {
 }
 
 pub struct Compiler<'c> {
-    cmd: &'c String,
-    dm: &'c DepManager,
+    toolchain: Toolchain,
+    deps: DepGraph,
     std: Standard,
     proj_type: ProjType,
     proj_dir: &'c PathBuf,
+    /// Output directory of the profile and target
+    build_dir: PathBuf,
+    pool: JobPool,
+    pub profile: &'c Profile,
     pub root_name: &'c str,
 }
 
+/// File name in the object directory that stores the
+/// flags the objects were compiled with
+const FLAGS_FILE: &str = ".flags";
+
+/// Directory in the build directory that dependencies are built into
+pub const DEPS_DIR: &str = "deps";
+
+/// A single translation unit that is compiled
+/// into its own object file
+pub struct Unit {
+    pub src: PathBuf,
+    pub obj: PathBuf,
+    pub dep: PathBuf,
+}
+
 pub struct CompileCtx<'ctx> {
     pub out_dir: &'ctx Path,
+    /// Name of the executable, or of the library
+    /// without the `lib` prefix and file extension
     pub out_name: &'ctx str,
     pub root_dir: &'ctx Path,
     pub excluded: &'ctx HashSet<PathBuf>,
+    pub lib_kind: LibKind,
+    pub version: &'ctx str,
+    pub cflags: &'ctx [String],
+    pub ldflags: &'ctx [String],
+    /// Public include directories of the project
+    pub include_dirs: &'ctx [PathBuf],
+    /// Indices of the direct dependencies in the dependency graph
+    pub deps: &'ctx [usize],
+    /// Executables that are built
+    pub bins: &'ctx [Bin],
+    /// Sources generated by build scripts,
+    /// compiled together with the ones in `src/`
+    pub sources: &'ctx [PathBuf],
 }
 
 impl<'c> Compiler<'c> {
-    pub fn new(cur_dir: &'c PathBuf, cfg: &'c Config) -> anyhow::Result<Self> {
+    pub fn new(
+        cur_dir: &'c PathBuf,
+        cfg: &'c Config,
+        profile: &str,
+        target: Option<&Target>,
+        mut deps: DepGraph,
+        jobs: usize,
+    ) -> anyhow::Result<Self> {
         let root_name =
             util::root_dir_name(cur_dir).context("Failed to get root name of project")?;
+        deps.mark_pic(cfg.proj_type == ProjType::Lib && cfg.lib_kind.is_shared());
         Ok(Self {
-            cmd: &cfg.compiler,
-            dm: &cfg.deps,
+            toolchain: Toolchain::detect(&cfg.compiler, target)?,
+            deps,
             proj_type: cfg.proj_type,
             std: cfg.c_std,
             proj_dir: cur_dir,
+            build_dir: cur_dir.join(build_dir(profile, target)),
+            pool: JobPool::new(jobs),
+            profile: cfg.profile(profile)?,
             root_name,
         })
     }
 
     #[inline(always)]
-    pub fn build(
-        &self,
-        ctx: CompileCtx<'c>,
-        enable_dbg: bool,
-        is_release: bool,
-        tests: bool,
-    ) -> anyhow::Result<()> {
+    pub fn build(&self, ctx: &CompileCtx, tests: bool) -> anyhow::Result<()> {
         match self.proj_type {
             ProjType::Lib => self.build_lib(ctx),
-            ProjType::Bin => self.build_exe(ctx, enable_dbg, is_release, tests),
+            ProjType::Bin => self.build_exe(ctx, tests),
         }
     }
 
-    pub fn build_exe(
-        &self,
-        ctx: CompileCtx<'c>,
-        enable_dbg: bool,
-        is_release: bool,
-        tests: bool,
-    ) -> anyhow::Result<()> {
-        let standard = format!("-std={}", self.std);
-        let mut program = Command::new(self.cmd);
-        let mut src_files = util::get_src_files(&ctx.root_dir.join("src"));
-        src_files.retain(|e| !ctx.excluded.contains(e));
+    /// Build the executables of the context. The sources
+    /// that aren't the entry of an executable are compiled
+    /// once and linked into every executable
+    pub fn build_exe(&self, ctx: &CompileCtx, tests: bool) -> anyhow::Result<()> {
+        let mut units = self.exe_units(ctx);
+        let shared_len = units.len();
+        units.extend(self.entry_units(ctx));
+        let flags = self.exe_flags(ctx, tests);
+        let objects = self.compile_units(&units, &ctx.out_dir.join("obj"), &flags)?;
+        let (shared, entries) = objects.split_at(shared_len);
+
+        for (bin, entry) in ctx.bins.iter().zip(entries) {
+            let mut program = self.toolchain.command();
+            program
+                .arg(entry)
+                .args(shared)
+                .arg("-o")
+                .arg(ctx.out_dir.join(&bin.name));
+
+            self.link_lib(&mut program)?;
+            program.args(self.toolchain.link_args()).args(ctx.ldflags);
+
+            let status = program.status().context("Failed to link executable")?;
+            if !status.success() {
+                bail!("Failed to link executable: {}", bin.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Build examples of a library project, every
+    /// example is linked against the library that was
+    /// built with the context, and its dependencies
+    pub fn build_examples(&self, ctx: &CompileCtx, examples: &[Bin]) -> anyhow::Result<()> {
+        let examples_dir = ctx.out_dir.join("examples");
+        let obj_dir = examples_dir.join("obj");
+        let units: Vec<Unit> = examples
+            .iter()
+            .flat_map(|example| {
+                Self::units(
+                    HashSet::from([example.entry.clone()]),
+                    &ctx.root_dir.join("examples"),
+                    &obj_dir,
+                )
+            })
+            .collect();
+        let flags = self.exe_flags(ctx, false);
+        let objects = self.compile_units(&units, &obj_dir, &flags)?;
+        // Libraries without sources aren't built
+        let has_lib = !self.lib_units(ctx).is_empty();
+
+        for (example, obj) in examples.iter().zip(&objects) {
+            let mut program = self.toolchain.command();
+            program
+                .arg(obj)
+                .arg("-o")
+                .arg(examples_dir.join(&example.name));
+
+            if has_lib {
+                if ctx.lib_kind.is_static() {
+                    program.arg(ctx.out_dir.join(format!("{}.a", ctx.out_name)));
+                } else {
+                    let lib_dir = ctx.root_dir.join(ctx.out_dir);
+                    program
+                        .arg(lib_dir.join(format!("lib{}.so", ctx.out_name)))
+                        .arg(self.toolchain.rpath_flag(&lib_dir));
+                }
+            }
+            self.link_lib(&mut program)?;
+            program.args(self.toolchain.link_args()).args(ctx.ldflags);
+
+            let status = program.status().context("Failed to link example")?;
+            if !status.success() {
+                bail!("Failed to link example: {}", example.name);
+            }
+        }
+        Ok(())
+    }
 
-        if enable_dbg {
-            program.arg("-g");
-        } else if is_release {
-            program.arg("-o3");
+    /// Sources of the test binary, the sources of
+    /// the project without entry files and the
+    /// files in `tests/`
+    pub fn test_sources(&self, ctx: &CompileCtx) -> Vec<PathBuf> {
+        let units = match self.proj_type {
+            ProjType::Lib => self.lib_units(ctx),
+            ProjType::Bin => self.exe_units(ctx),
+        };
+        let mut sources: Vec<PathBuf> = units.into_iter().map(|unit| unit.src).collect();
+        let tests_dir = ctx.root_dir.join("tests");
+        if tests_dir.is_dir() {
+            let mut tests: Vec<PathBuf> = util::get_src_files(&tests_dir).into_iter().collect();
+            tests.sort();
+            sources.extend(tests);
         }
+        sources
+    }
 
+    /// Build the test binary `out_dir/<out_name>` from the
+    /// test sources and the generated `runner`
+    pub fn build_tests(&self, ctx: &CompileCtx, runner: &Path) -> anyhow::Result<()> {
+        let obj_dir = ctx.out_dir.join("obj");
+        let mut units = Self::units(
+            self.test_sources(ctx).into_iter().collect(),
+            ctx.root_dir,
+            &obj_dir,
+        );
+        units.extend(Self::units(
+            HashSet::from([runner.to_path_buf()]),
+            ctx.out_dir,
+            &obj_dir,
+        ));
+        let flags = self.exe_flags(ctx, true);
+        let objects = self.compile_units(&units, &obj_dir, &flags)?;
+
+        let mut program = self.toolchain.command();
         program
-            .args(src_files)
+            .args(&objects)
             .arg("-o")
             .arg(ctx.out_dir.join(ctx.out_name));
+        self.link_lib(&mut program)?;
+        program.args(self.toolchain.link_args()).args(ctx.ldflags);
 
-        if !tests {
-            program.arg("-DNOTESTS");
+        let status = program.status().context("Failed to link tests")?;
+        if !status.success() {
+            bail!("Failed to link tests: {}", ctx.out_name);
+        }
+        Ok(())
+    }
+
+    pub fn build_lib(&self, ctx: &CompileCtx) -> anyhow::Result<()> {
+        let units = self.lib_units(ctx);
+
+        if units.is_empty() {
+            return Ok(());
         }
 
-        program.arg(standard);
+        let flags = self.lib_flags(ctx);
+        let objects = self.compile_units(&units, &ctx.out_dir.join("obj"), &flags)?;
 
-        self.link_lib(&mut program)?;
+        if ctx.lib_kind.is_static() {
+            self.archive_lib(ctx, &objects)?;
+        }
+        if ctx.lib_kind.is_shared() {
+            self.link_shared_lib(ctx, &objects)?;
+        }
+        Ok(())
+    }
 
-        program
+    /// Bundle the objects into a static library (`<name>.a`)
+    fn archive_lib(&self, ctx: &CompileCtx, objects: &[PathBuf]) -> anyhow::Result<()> {
+        let out_path = ctx.out_dir.join(format!("{}.a", ctx.out_name));
+        if out_path.exists() {
+            fs::remove_file(&out_path).context("Failed to remove old library archive")?;
+        }
+        let status = self
+            .toolchain
+            .archive(&out_path, objects)
+            .status()
+            .context("Failed to link library")?;
+        if !status.success() {
+            bail!("Failed to link library: {}", ctx.out_name);
+        }
+        Ok(())
+    }
+
+    /// Link the objects into a shared library.
+    /// The library is written to `lib<name>.so.<version>`, with
+    /// symlinks for the soname and for `lib<name>.so`
+    fn link_shared_lib(&self, ctx: &CompileCtx, objects: &[PathBuf]) -> anyhow::Result<()> {
+        let link_name = format!("lib{}.so", ctx.out_name);
+        let soname = format!("{}.{}", link_name, soversion(ctx.version));
+        let file_name = format!("{}.{}", link_name, ctx.version);
+
+        let mut cmd = self.toolchain.command();
+        cmd.arg("-shared")
+            .arg(self.toolchain.soname_flag(&soname))
+            .arg("-o")
+            .arg(ctx.out_dir.join(&file_name))
+            .args(objects);
+        // The library has to be loadable on its own, with `dlopen` too
+        self.link_deps(&mut cmd, ctx.deps)?;
+        let status = cmd
+            .args(self.toolchain.link_args())
+            .args(ctx.ldflags)
             .status()
-            .context("Failed to build executable")
-            .map(|_| ())
+            .context("Failed to link shared library")?;
+        if !status.success() {
+            bail!("Failed to link shared library: {}", ctx.out_name);
+        }
+
+        for link in [&soname, &link_name] {
+            // The soname is the file name itself for versions like `0.1`
+            if *link == file_name {
+                continue;
+            }
+            let link_path = ctx.out_dir.join(link);
+            if link_path.symlink_metadata().is_ok() {
+                fs::remove_file(&link_path)?;
+            }
+            link_alias(&file_name, &link_path)?;
+        }
+        Ok(())
     }
 
-    pub fn build_lib(&self, ctx: CompileCtx<'c>) -> anyhow::Result<()> {
-        let standard = format!("-std={}", self.std);
-        let mut src_files = util::get_src_files(&ctx.root_dir.join("src"));
-        src_files.remove(&ctx.root_dir.join("src").join("lib.c"));
+    fn exe_units(&self, ctx: &CompileCtx) -> Vec<Unit> {
+        let src_dir = ctx.root_dir.join("src");
+        let mut src_files = util::get_src_files(&src_dir);
         src_files.retain(|e| !ctx.excluded.contains(e));
-        let mut out_names = Vec::new();
+        let mut units = Self::units(src_files, &src_dir, &ctx.out_dir.join("obj"));
+        units.extend(self.generated_units(ctx));
+        units
+    }
 
-        if src_files.is_empty() {
-            return Ok(());
+    /// Units of the sources generated by build scripts
+    fn generated_units(&self, ctx: &CompileCtx) -> Vec<Unit> {
+        Self::units(
+            ctx.sources.iter().cloned().collect(),
+            ctx.root_dir,
+            &ctx.out_dir.join("obj").join("generated"),
+        )
+    }
+
+    /// Units of the entry files of the executables,
+    /// in the same order as the executables
+    fn entry_units(&self, ctx: &CompileCtx) -> Vec<Unit> {
+        let src_dir = ctx.root_dir.join("src");
+        let obj_dir = ctx.out_dir.join("obj");
+        ctx.bins
+            .iter()
+            .flat_map(|bin| Self::units(HashSet::from([bin.entry.clone()]), &src_dir, &obj_dir))
+            .collect()
+    }
+
+    fn exe_flags(&self, ctx: &CompileCtx, tests: bool) -> Vec<String> {
+        let mut flags = ctx.cflags.to_vec();
+        if !tests {
+            flags.push("-DNOTESTS".into());
         }
+        flags.push(format!("-std={}", self.std));
+        flags.extend(self.include_flags(ctx));
+        flags
+    }
 
-        // TODO: Sort all object files into directory
+    fn lib_units(&self, ctx: &CompileCtx) -> Vec<Unit> {
+        let src_dir = ctx.root_dir.join("src");
+        let mut src_files = util::get_src_files(&src_dir);
+        src_files.remove(&src_dir.join("lib.c"));
+        src_files.retain(|e| !ctx.excluded.contains(e));
+        let mut units = Self::units(src_files, &src_dir, &ctx.out_dir.join("obj"));
+        units.extend(self.generated_units(ctx));
+        units
+    }
 
-        for file in src_files {
-            let mut program = Command::new(self.cmd);
-            // TODO: Clean this up
-            let name = file.file_name().unwrap().to_string_lossy().to_string();
-            let out_path = ctx.out_dir.join(&name[..name.len() - 2]);
-            program
-                .arg("-c")
-                .arg(&file)
-                .arg("-o")
-                .arg(&out_path)
-                .arg("-w")
-                .arg("-DNOTESTS")
-                .arg(&standard);
-            program
-                .spawn()
-                .context(format!("Failed to compile src file: {}", &file.display()))?
-                .wait()
-                .context("Failed to wait for src file to compile")?;
-            out_names.push(out_path);
-        }
-        let mut linker = Command::new("ar");
-        linker
-            .arg("rcs")
-            .arg(ctx.out_dir.join(ctx.out_name))
-            .args(out_names);
-        linker.spawn().context("Failed to link library")?;
+    fn lib_flags(&self, ctx: &CompileCtx) -> Vec<String> {
+        let mut flags = ctx.cflags.to_vec();
+        flags.push("-DNOTESTS".into());
+        flags.push(format!("-std={}", self.std));
+        if ctx.lib_kind.is_shared() {
+            flags.push("-fPIC".into());
+        }
+        flags.extend(self.include_flags(ctx));
+        flags
+    }
+
+    /// Include paths of the project's own headers and of
+    /// the public headers of its direct dependencies.
+    /// Headers of dependencies of dependencies are not
+    /// visible, just like their private headers
+    fn include_flags(&self, ctx: &CompileCtx) -> Vec<String> {
+        let mut dirs = ctx.include_dirs.to_vec();
+        dirs.push(ctx.root_dir.join("src"));
+        for &dep in ctx.deps {
+            dirs.extend(self.deps.nodes[dep].include_dirs.iter().cloned());
+        }
+
+        let mut flags = Vec::new();
+        for dir in dirs {
+            let flag = format!("-I{}", dir.display());
+            if !flags.contains(&flag) {
+                flags.push(flag);
+            }
+        }
+        flags
+    }
+
+    /// Map every source file to its object- and depfile
+    /// below `obj_dir`, mirroring the layout of `src_dir`.
+    /// Files outside of `src_dir` mirror their absolute path
+    fn units(src_files: HashSet<PathBuf>, src_dir: &Path, obj_dir: &Path) -> Vec<Unit> {
+        let mut units: Vec<Unit> = src_files
+            .into_iter()
+            .map(|src| {
+                let rel: PathBuf = match src.strip_prefix(src_dir) {
+                    Ok(rel) => rel.to_path_buf(),
+                    Err(_) => src
+                        .components()
+                        .filter(|part| matches!(part, Component::Normal(_)))
+                        .collect(),
+                };
+                let obj = obj_dir.join(&rel).with_extension("o");
+                let dep = obj_dir.join(&rel).with_extension("d");
+                Unit { src, obj, dep }
+            })
+            .collect();
+        units.sort_by(|a, b| a.src.cmp(&b.src));
+        units
+    }
+
+    /// Arguments passed to the compiler to compile a unit
+    fn compile_args(&self, unit: &Unit, flags: &[String]) -> Vec<OsString> {
+        let mut args = self.toolchain.compile_args(&unit.src, &unit.obj, &unit.dep);
+        args.extend(flags.iter().map(OsString::from));
+        args
+    }
+
+    /// Compile a single unit.
+    /// The compiler output is buffered and printed at once,
+    /// so diagnostics of units that are compiled in parallel
+    /// don't interleave
+    fn compile_unit(&self, unit: &Unit, flags: &[String]) -> anyhow::Result<()> {
+        if let Some(parent) = unit.obj.parent() {
+            fs::create_dir_all(parent).context("Failed to create object directory")?;
+        }
+        let mut program = self.toolchain.command();
+        program.args(self.compile_args(unit, flags));
+        if let Some(color_flag) = self.toolchain.color_flag() {
+            if io::stderr().is_terminal() {
+                program.arg(color_flag);
+            }
+        }
+        let output = program.output().context(format!(
+            "Failed to compile src file: {}",
+            unit.src.display()
+        ))?;
+
+        if !output.stdout.is_empty() || !output.stderr.is_empty() {
+            let mut stderr = io::stderr().lock();
+            stderr.write_all(&output.stdout)?;
+            stderr.write_all(&output.stderr)?;
+        }
+
+        if !output.status.success() {
+            bail!("Failed to compile src file: {}", unit.src.display());
+        }
         Ok(())
     }
 
+    /// Compile all units that are out of date and return
+    /// the object files of all units.
+    /// If the flags changed since the last build, every
+    /// unit is recompiled.
+    fn compile_units(
+        &self,
+        units: &[Unit],
+        obj_dir: &Path,
+        flags: &[String],
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        fs::create_dir_all(obj_dir).context("Failed to create object directory")?;
+
+        let flags_path = obj_dir.join(FLAGS_FILE);
+        let flags_str = format!("{}\n{}", self.toolchain.cc, flags.join("\n"));
+        let flags_changed = fs::read_to_string(&flags_path)
+            .map(|old| old != flags_str)
+            .unwrap_or(true);
+
+        let stale: Vec<&Unit> = units
+            .iter()
+            .filter(|unit| flags_changed || depfile::is_stale(&unit.src, &unit.obj, &unit.dep))
+            .collect();
+
+        let next = AtomicUsize::new(0);
+        let failed = Mutex::new(Vec::new());
+        let workers = self.pool.jobs().min(stale.len());
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    while let Some(unit) = stale.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let _token = self.pool.acquire();
+                        if let Err(err) = self.compile_unit(unit, flags) {
+                            failed.lock().unwrap().push(err);
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut failed = failed.into_inner().unwrap();
+        match failed.len() {
+            0 => (),
+            1 => return Err(failed.remove(0)),
+            len => bail!("Failed to compile {} src files", len),
+        }
+
+        fs::write(&flags_path, flags_str).context("Failed to write compile flags")?;
+
+        Ok(units.iter().map(|unit| unit.obj.clone()).collect())
+    }
+
+    /// Link all dependencies, dependents are passed
+    /// to the linker before their dependencies.
+    /// Dependencies that are only built as shared libraries
+    /// are linked dynamically and found through the rpath
     pub fn link_lib(&self, cmd: &mut Command) -> anyhow::Result<()> {
-        cmd.arg("-Lbuild/");
-        for dep in &self.dm.deps {
-            let name = dep.name()?;
-            cmd.arg(format!("-l:{}/{}.a", name, name));
+        self.link_deps(cmd, &self.deps.roots)
+    }
+
+    /// Link the dependencies `deps` and everything they depend on
+    fn link_deps(&self, cmd: &mut Command, deps: &[usize]) -> anyhow::Result<()> {
+        for node in self.deps.link_order(deps) {
+            let lib_dir = self.dep_dir(&node.name);
+            if node.lib_kind.is_static() {
+                cmd.arg(lib_dir.join(format!("{}.a", node.name)));
+            } else {
+                cmd.arg(lib_dir.join(format!("lib{}.so", node.name)))
+                    .arg(self.toolchain.rpath_flag(&lib_dir));
+            }
         }
         Ok(())
     }
 
+    /// Build all dependencies.
+    /// Dependencies that don't depend on each
+    /// other are built at the same time
     pub fn build_deps(&self) -> anyhow::Result<()> {
-        for dep in &self.dm.deps {
-            let out_dir = self.proj_dir.join("build").join(dep.name()?);
-            if !out_dir.exists() {
-                fs::create_dir(&out_dir)?;
-            }
-            let mut name = dep.name()?;
-            name.push_str(".a");
-            let cfg = dep.config()?;
-            let ctx = CompileCtx {
-                out_dir: &out_dir,
-                out_name: &name,
-                root_dir: &dep.location()?,
-                excluded: &cfg.excluded,
-            };
-            self.build_lib(ctx)
-                .context(format!("Failed to build library {}", name))?;
+        for level in self.deps.levels() {
+            thread::scope(|scope| {
+                let handles: Vec<_> = level
+                    .iter()
+                    .map(|node| scope.spawn(|| self.build_dep(node)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .try_for_each(|handle| handle.join().unwrap())
+            })?;
         }
         Ok(())
     }
+
+    fn build_dep(&self, node: &DepNode) -> anyhow::Result<()> {
+        self.with_dep_ctx(node, |ctx| self.build_lib(&ctx))
+            .context(format!("Failed to build library {}", node.name))
+    }
+
+    /// Output directory of a dependency. Dependencies have their own
+    /// directory, so their names can't clash with the project's outputs
+    #[inline(always)]
+    fn dep_dir(&self, name: &str) -> PathBuf {
+        self.build_dir.join(DEPS_DIR).join(name)
+    }
+
+    /// Create the compile context of a dependency
+    /// and pass it to `f`
+    fn with_dep_ctx<T>(
+        &self,
+        node: &DepNode,
+        f: impl FnOnce(CompileCtx) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let out_dir = self.dep_dir(&node.name);
+        if !out_dir.exists() {
+            fs::create_dir_all(&out_dir)?;
+        }
+        // Warnings of dependencies are not the concern of the project
+        let mut cflags = self.profile.codegen_flags();
+        cflags.push("-w".into());
+        cflags.extend(features::define_flags(&node.defines));
+        // Static libraries that end up in a shared one
+        if node.pic && !node.lib_kind.is_shared() {
+            cflags.push("-fPIC".into());
+        }
+        f(CompileCtx {
+            out_dir: &out_dir,
+            out_name: &node.name,
+            root_dir: &node.location,
+            excluded: &node.excluded,
+            lib_kind: node.lib_kind,
+            version: &node.version,
+            cflags: &cflags,
+            ldflags: &[],
+            include_dirs: &node.include_dirs,
+            deps: &node.deps,
+            bins: &[],
+            sources: &[],
+        })
+    }
+
+    /// Indices of the direct dependencies of the project
+    #[inline(always)]
+    pub fn dep_roots(&self) -> &[usize] {
+        &self.deps.roots
+    }
+
+    /// Compile commands of every unit of the project
+    /// and of its dependencies, exactly as they are
+    /// passed to the compiler when building. The sources
+    /// are compiled like the executables of the context if
+    /// there are any, like the library otherwise
+    pub fn compdb_entries(
+        &self,
+        ctx: &CompileCtx,
+        tests: bool,
+    ) -> anyhow::Result<Vec<compdb::Entry>> {
+        let (units, flags) = match ctx.bins.is_empty() {
+            true => (self.lib_units(ctx), self.lib_flags(ctx)),
+            false => {
+                let mut units = self.exe_units(ctx);
+                units.extend(self.entry_units(ctx));
+                (units, self.exe_flags(ctx, tests))
+            }
+        };
+        let mut entries = self.unit_entries(&units, &flags);
+
+        for node in &self.deps.nodes {
+            let dep_entries = self.with_dep_ctx(node, |ctx| {
+                Ok(self.unit_entries(&self.lib_units(&ctx), &self.lib_flags(&ctx)))
+            })?;
+            entries.extend(dep_entries);
+        }
+        Ok(entries)
+    }
+
+    fn unit_entries(&self, units: &[Unit], flags: &[String]) -> Vec<compdb::Entry> {
+        units
+            .iter()
+            .map(|unit| {
+                let mut arguments = vec![self.toolchain.cc.clone()];
+                arguments.extend(
+                    self.compile_args(unit, flags)
+                        .iter()
+                        .map(|arg| arg.to_string_lossy().to_string()),
+                );
+                compdb::Entry {
+                    directory: self.proj_dir.clone(),
+                    file: self.proj_dir.join(&unit.src),
+                    output: self.proj_dir.join(&unit.obj),
+                    arguments,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Output directory of a build relative to the project,
+/// `build/<profile>/` or `build/<triple>/<profile>/` for targets
+pub fn build_dir(profile: &str, target: Option<&Target>) -> PathBuf {
+    let mut dir = PathBuf::from("build");
+    if let Some(target) = target {
+        dir.push(&target.triple);
+    }
+    dir.join(profile)
+}
+
+/// Make `link_path` point to `file_name` in the same directory
+#[cfg(unix)]
+fn link_alias(file_name: &str, link_path: &Path) -> anyhow::Result<()> {
+    std::os::unix::fs::symlink(file_name, link_path)
+        .context(format!("Failed to create symlink: {}", link_path.display()))
+}
+
+/// Platforms without symlinks get a copy of the library
+#[cfg(not(unix))]
+fn link_alias(file_name: &str, link_path: &Path) -> anyhow::Result<()> {
+    let file_path = link_path.with_file_name(file_name);
+    fs::copy(&file_path, link_path)
+        .map(|_| ())
+        .context(format!("Failed to copy library: {}", link_path.display()))
+}
+
+/// The part of the version that is part of the soname.
+/// Versions below 1.0 break compatibility with
+/// every minor version, so it is included for them
+fn soversion(version: &str) -> String {
+    let mut parts = version.split('.');
+    let major = parts.next().unwrap_or("0");
+    match (major, parts.next()) {
+        ("0", Some(minor)) => format!("0.{}", minor),
+        _ => major.to_string(),
+    }
 }
@@ -0,0 +1,192 @@
+//! The C toolchains surtur knows how to drive.
+//! Everything that differs between compilers,
+//! like depfile, archive and link flags, lives here
+//! so the compiler module stays toolchain agnostic
+
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Context};
+
+use crate::cli::target::Target;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Family {
+    Gcc,
+    Clang,
+    /// The Tiny C Compiler, compiles very fast
+    /// which makes it handy for dev builds
+    Tcc,
+}
+
+impl Display for Family {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Family::Gcc => "gcc",
+            Family::Clang => "clang",
+            Family::Tcc => "tcc",
+        })
+    }
+}
+
+pub struct Toolchain {
+    /// The compiler command, `Props.compiler` with the prefix of the target
+    pub cc: String,
+    pub family: Family,
+    /// Prefix of the archiver, the same as the one of the compiler
+    prefix: String,
+    /// Passed to every invocation of the compiler, like `--sysroot`
+    args: Vec<String>,
+    cflags: Vec<String>,
+    ldflags: Vec<String>,
+}
+
+impl Toolchain {
+    /// Detect the toolchain of `compiler` that builds for `target`,
+    /// or for this machine if there is no target
+    pub fn detect(compiler: &str, target: Option<&Target>) -> anyhow::Result<Self> {
+        let family = probe(compiler)?;
+        let Some(target) = target else {
+            return Ok(Self {
+                cc: compiler.to_string(),
+                family,
+                prefix: String::new(),
+                args: Vec::new(),
+                cflags: Vec::new(),
+                ldflags: Vec::new(),
+            });
+        };
+
+        let prefix = match (&target.prefix, family) {
+            (Some(prefix), _) => prefix.clone(),
+            // Clang is a cross compiler by itself
+            (None, Family::Clang) => String::new(),
+            (None, _) => format!("{}-", target.triple),
+        };
+        let mut args = Vec::new();
+        if family == Family::Clang {
+            args.push(format!("--target={}", target.triple));
+        }
+        if let Some(sysroot) = &target.sysroot {
+            args.push(format!("--sysroot={}", sysroot.display()));
+        }
+
+        let cc = format!("{}{}", prefix, compiler);
+        Ok(Self {
+            family: if prefix.is_empty() {
+                family
+            } else {
+                probe(&cc)?
+            },
+            cc,
+            prefix,
+            args,
+            cflags: target.cflags.clone(),
+            ldflags: target.ldflags.clone(),
+        })
+    }
+
+    #[inline(always)]
+    pub fn command(&self) -> Command {
+        Command::new(&self.cc)
+    }
+
+    /// Arguments to compile `src` into the object `obj`,
+    /// writing the headers it includes to the depfile `dep`
+    pub fn compile_args(&self, src: &Path, obj: &Path, dep: &Path) -> Vec<OsString> {
+        let depfile_flag = match self.family {
+            Family::Gcc | Family::Clang => "-MMD",
+            // tcc has no way to leave out system headers
+            Family::Tcc => "-MD",
+        };
+        let mut args: Vec<OsString> = self.args.iter().map(OsString::from).collect();
+        args.extend([
+            "-c".into(),
+            src.into(),
+            "-o".into(),
+            obj.into(),
+            depfile_flag.into(),
+            "-MF".into(),
+            dep.into(),
+        ]);
+        args.extend(self.cflags.iter().map(OsString::from));
+        args
+    }
+
+    /// Arguments passed to every link of an executable or shared library
+    pub fn link_args(&self) -> Vec<String> {
+        let mut args = self.args.clone();
+        args.extend(self.ldflags.iter().cloned());
+        args
+    }
+
+    /// Flag to keep colored diagnostics when the
+    /// output of the compiler is captured
+    pub fn color_flag(&self) -> Option<&'static str> {
+        match self.family {
+            Family::Gcc | Family::Clang => Some("-fdiagnostics-color=always"),
+            Family::Tcc => None,
+        }
+    }
+
+    /// Command that bundles the objects into the static library `out`
+    pub fn archive(&self, out: &Path, objects: &[PathBuf]) -> Command {
+        let mut cmd = match self.family {
+            Family::Gcc | Family::Clang => Command::new(format!("{}ar", self.prefix)),
+            Family::Tcc => {
+                let mut cmd = self.command();
+                cmd.arg("-ar");
+                cmd
+            }
+        };
+        cmd.arg("rcs").arg(out).args(objects);
+        cmd
+    }
+
+    /// Linker flag to set the soname of a shared library
+    pub fn soname_flag(&self, soname: &str) -> String {
+        match self.family {
+            Family::Gcc | Family::Clang => format!("-Wl,-soname,{}", soname),
+            Family::Tcc => format!("-Wl,-soname={}", soname),
+        }
+    }
+
+    /// Linker flag to find shared libraries in `dir` at runtime
+    pub fn rpath_flag(&self, dir: &Path) -> String {
+        match self.family {
+            Family::Gcc | Family::Clang => format!("-Wl,-rpath,{}", dir.display()),
+            Family::Tcc => format!("-Wl,-rpath={}", dir.display()),
+        }
+    }
+}
+
+/// Detect the family of the compiler by probing `--version`
+fn probe(cc: &str) -> anyhow::Result<Family> {
+    let output = Command::new(cc)
+        .arg("--version")
+        .output()
+        .context(format!("Failed to run compiler `{}`, is it installed?", cc))?;
+    // tcc prints its version to stderr
+    let version = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    if version.contains("clang version") {
+        Ok(Family::Clang)
+    } else if version.starts_with("tcc version") {
+        Ok(Family::Tcc)
+    } else if version.contains("Free Software Foundation") {
+        Ok(Family::Gcc)
+    } else {
+        bail!(
+            "Unknown compiler `{}`, supported compilers are gcc, clang and tcc",
+            cc
+        )
+    }
+}
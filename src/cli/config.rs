@@ -1,21 +1,30 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeSet, HashMap, HashSet},
     fmt::Display,
+    fs,
     path::{Path, PathBuf},
+    rc::Rc,
 };
 
 use anyhow::{bail, Context};
 /// Handling of the project's lua config file.
 /// It includes the lua parser and all information
 /// related to the project's configuration
-use mlua::{Lua, Table, Value};
+use mlua::{FromLua, Lua, Table, Value};
 
-use crate::util::{files::FileHandler, DEFAULT_COMPILER};
+use crate::util::{self, files::FileHandler, DEFAULT_COMPILER};
 
 use super::{
+    codegen::{self, Generator},
+    commands::{self, CustomCommand},
     compiler::{Standard, STANDARDS},
-    deps::{DepManager, Dependency},
-    scripts::ScriptManager,
+    deps::{DepManager, Dependency, GitRef},
+    features::Features,
+    harness::Timeouts,
+    profile::{self, Profile},
+    sandbox,
+    scripts::{Script, ScriptManager},
+    target::{self, Target},
 };
 
 // TODO: Seperate tables from rest of the struct so it represents the actual config file
@@ -25,10 +34,44 @@ pub struct Config {
     pub c_std: Standard,
     pub proj_version: String,
     pub proj_type: ProjType,
+    pub lib_kind: LibKind,
     pub deps: DepManager,
+    /// Entry file of the project, `src/main.c` for
+    /// binaries and `src/lib.c` for libraries by default
     pub entry: PathBuf,
+    /// Executables of the project, the entry file, `src/bin/*.c`
+    /// and the `Bins` table. Their entry files are excluded
+    /// from the sources that are shared by all of them
+    pub bins: Vec<Bin>,
+    /// Programs in `examples/` that use the library of the project
+    pub examples: Vec<Bin>,
     pub excluded: HashSet<PathBuf>,
+    /// Public include directories, projects that depend
+    /// on this one can include the headers in them
+    pub include_dirs: Vec<PathBuf>,
+    /// Macros that are always defined, either `NAME` or `NAME=VALUE`
+    pub defines: Vec<String>,
+    pub features: Features,
     pub scripts: Option<ScriptManager>,
+    /// Commands that generate sources into `build/gen/`
+    pub generators: Vec<Generator>,
+    pub compdb: bool,
+    pub profiles: HashMap<String, Profile>,
+    pub targets: HashMap<String, Target>,
+    pub test_timeouts: Timeouts,
+    /// Commands from the `Commands` table, run with `surtur x <name>`
+    pub commands: Vec<CustomCommand>,
+    /// State the config was evaluated in, lua
+    /// functions of the config are called in it
+    pub lua: Rc<Lua>,
+}
+
+/// An executable of the project, built from its entry
+/// file and the sources that are shared by all executables
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Bin {
+    pub name: String,
+    pub entry: PathBuf,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
@@ -46,25 +89,56 @@ impl Display for ProjType {
     }
 }
 
+/// What kind of library a library project is built as
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum LibKind {
+    /// Static archive (`<name>.a`)
+    Static,
+    /// Shared object (`lib<name>.so`) that can be linked dynamically or loaded with `dlopen`
+    Shared,
+    Both,
+}
+
+impl LibKind {
+    #[inline(always)]
+    pub fn is_static(self) -> bool {
+        self != LibKind::Shared
+    }
+
+    #[inline(always)]
+    pub fn is_shared(self) -> bool {
+        self != LibKind::Static
+    }
+}
+
+impl Display for LibKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LibKind::Static => "static",
+            LibKind::Shared => "shared",
+            LibKind::Both => "both",
+        })
+    }
+}
+
 impl Config {
     pub fn parse(root_dir: &Path, file: FileHandler) -> anyhow::Result<Self> {
         let mut dependencies = HashSet::new();
         let mut c_std_str = String::from("c17");
         let mut proj_version = None;
         let mut proj_type = ProjType::Lib;
+        let mut lib_kind = LibKind::Static;
         let mut compiler = String::from(DEFAULT_COMPILER);
         let mut excluded: HashSet<PathBuf> = HashSet::new();
+        let mut compdb = false;
 
         let mut c_std: Option<Standard> = None;
 
-        let lua = Lua::new();
-        //let function = lua.create_function(builtins::run_script).expect("Failed to create function");
+        // Shared with the config, the tables below borrow it.
+        // The config can't touch the system, see `sandbox`
+        let lua = Rc::new(sandbox::new()?);
 
-        //lua.globals().set("run_cmd", function).expect("Failed to set global function: \"run_cmd\"");
-
-        lua.load(&file.file_content)
-            .exec()
-            .expect("Failed to load context");
+        sandbox::eval(&lua, "project.lua", &file.file_content)?;
 
         let name: String = lua
             .globals()
@@ -82,19 +156,45 @@ impl Config {
 
         let scripts_table: Option<Table> = lua.globals().get("Scripts").ok();
 
-        let entry: String = lua.globals().get("Entry").unwrap_or(
-            match proj_type {
-                ProjType::Lib => "lib.c",
-                ProjType::Bin => "main.c",
-            }
-            .into(),
-        );
+        let generators = codegen::parse_generators(lua.globals().get("Generators").ok())
+            .context("Failed to parse generators")?;
+
+        let commands = commands::parse_commands(&lua, lua.globals().get("Commands").ok())
+            .context("Failed to parse commands")?;
+
+        let profiles_table: Option<Table> = lua.globals().get("Profiles").ok();
+
+        let targets_table: Option<Table> = lua.globals().get("Targets").ok();
+
+        let entry: Option<String> = lua.globals().get("Entry").ok();
+
+        let bins_table: Option<Table> = lua.globals().get("Bins").ok();
 
         let excluded_table: Option<Table> = lua.globals().get("Exclude").ok();
 
-        for pair in props_table.pairs::<String, String>() {
+        let include_table: Option<Table> = lua.globals().get("Include").ok();
+
+        let defines_table: Option<Table> = lua.globals().get("Defines").ok();
+
+        let test_timeouts =
+            Timeouts::parse(lua.globals().get("Tests").ok()).context("Failed to parse `Tests`")?;
+
+        let features = Features::parse(lua.globals().get("Features").ok())
+            .context("Failed to parse features")?;
+
+        for pair in props_table.pairs::<String, Value>() {
             let (key, val) = pair.expect("Failed to get pair");
-            match key.to_lowercase().as_str() {
+            let key = key.to_lowercase();
+            if key == "compdb" {
+                compdb = match val {
+                    Value::Boolean(val) => val,
+                    val => bail!("`compdb` has to be a boolean, found: {val:?}"),
+                };
+                continue;
+            }
+            let val = String::from_lua(val, &lua)
+                .context(format!("Invalid value for property: {}", key))?;
+            match key.as_str() {
                 "std" => c_std_str = val,
                 "version" => proj_version = Some(val),
                 "compiler" => compiler = val,
@@ -105,6 +205,14 @@ impl Config {
                         _ => bail!("`{}` is not a valid value for the projects type. Valid types are: `lib` and `bin`", val),
                     }
                 }
+                "kind" => {
+                    lib_kind = match val.as_str() {
+                        "static" => LibKind::Static,
+                        "shared" => LibKind::Shared,
+                        "both" => LibKind::Both,
+                        _ => bail!("`{}` is not a valid library kind. Valid kinds are: `static`, `shared` and `both`", val),
+                    }
+                }
                 key => bail!("invalid version entry: {}", key),
             }
         }
@@ -115,33 +223,180 @@ impl Config {
             }
         }
 
+        let src_dir = root_dir.join("src");
+        // Libraries only have an entry file if it's set explicitly,
+        // they use examples to run code instead
+        let has_entry = entry.is_some() || proj_type == ProjType::Bin;
+        let entry = src_dir.join(entry.as_deref().unwrap_or(match proj_type {
+            ProjType::Lib => "lib.c",
+            ProjType::Bin => "main.c",
+        }));
+
+        // The entry file is the binary named after the project directory,
+        // every file in `src/bin/` is a binary named after the file
+        let mut bins = Vec::new();
+        if has_entry && entry.is_file() {
+            let name =
+                util::root_dir_name(root_dir).context("Failed to get root name of project")?;
+            bins.push(Bin {
+                name: name.to_string(),
+                entry: entry.clone(),
+            });
+        }
+        bins.extend(discover_bins(&src_dir.join("bin")));
+        // Binaries of the `Bins` table replace discovered ones with the same name
+        if let Some(table) = bins_table {
+            for pair in table.pairs::<String, String>() {
+                let (name, path) = pair.context("`Bins` has to map binary names to entry files")?;
+                let bin = Bin {
+                    entry: src_dir.join(path),
+                    name,
+                };
+                if !bin.entry.is_file() {
+                    bail!(
+                        "Entry file of binary `{}` does not exist: {}",
+                        bin.name,
+                        bin.entry.display()
+                    );
+                }
+                match bins.iter_mut().find(|elem| elem.name == bin.name) {
+                    Some(elem) => *elem = bin,
+                    None => bins.push(bin),
+                }
+            }
+        }
+        // The binary of the entry file comes first, the others by name
+        bins.sort_by(|a, b| {
+            (a.entry != entry)
+                .cmp(&(b.entry != entry))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        for bin in &bins {
+            excluded.insert(bin.entry.clone());
+        }
+
+        let mut examples = discover_bins(&root_dir.join("examples"));
+        examples.sort_by(|a, b| a.name.cmp(&b.name));
+
+        // Public headers are in `include/` if it exists, otherwise in `src/`
+        let include_dirs = match include_table {
+            Some(table) => {
+                let mut dirs = Vec::new();
+                for dir in table.sequence_values::<String>() {
+                    let dir = root_dir.join(dir.context("Include directories have to be strings")?);
+                    if !dir.is_dir() {
+                        bail!("Include directory does not exist: {}", dir.display());
+                    }
+                    dirs.push(dir);
+                }
+                dirs
+            }
+            None if root_dir.join("include").is_dir() => vec![root_dir.join("include")],
+            None => vec![root_dir.join("src")],
+        };
+
+        // Defines are either a list like { "NAME", "NAME=VALUE" }
+        // or a table like { NAME = "VALUE" }
+        let mut defines = Vec::new();
+        if let Some(table) = defines_table {
+            for pair in table.pairs::<Value, Value>() {
+                let (key, val) = pair.context("Invalid entry in `Defines`")?;
+                let define = match (key, val) {
+                    (Value::Integer(_), Value::String(name)) => name.to_str()?.to_string(),
+                    (Value::String(name), Value::Boolean(true)) => name.to_str()?.to_string(),
+                    (Value::String(name), val) => format!(
+                        "{}={}",
+                        name.to_str()?,
+                        String::from_lua(val, &lua).context("Invalid value in `Defines`")?
+                    ),
+                    (key, val) => bail!("Invalid entry in `Defines`: {:?} = {:?}", key, val),
+                };
+                defines.push(define);
+            }
+        }
+        // Pairs of a table have no fixed order
+        defines.sort();
+
         // Iterating over dependencies
         if let Some(deps) = dep_table {
             for dep in deps.sequence_values::<Table>() {
-                let mut version = 0.0;
-                let mut origin = String::new();
                 let table = dep.context("Failed to get dependency table")?;
-                for pair in table.sequence_values::<Value>() {
-                    match pair.context("Failed to get dependency pair")? {
-                        Value::Integer(int_value) => {
-                            version = int_value as f64;
-                        }
-                        Value::String(string_value) => {
-                            origin = string_value.to_string_lossy().to_string();
-                        }
-                        Value::Number(num_value) => {
-                            version = num_value;
+
+                let mut dependency = match table.get::<_, Option<String>>("path")? {
+                    // Local dependency: { path = "../mylib" }
+                    Some(path) => Dependency::local(root_dir, &path),
+                    None => {
+                        let mut version = None;
+                        let mut origin = None;
+                        for pair in table.clone().sequence_values::<Value>() {
+                            match pair.context("Failed to get dependency pair")? {
+                                Value::String(string_value) => {
+                                    let string_value = string_value.to_string_lossy().to_string();
+                                    if origin.is_none() {
+                                        origin = Some(string_value);
+                                    } else if version.is_none() {
+                                        version = Some(string_value);
+                                    } else {
+                                        bail!(
+                                            "Too many values in dependency table: {string_value:?}"
+                                        )
+                                    }
+                                }
+                                Value::Integer(_) | Value::Number(_) => {
+                                    bail!("Dependency versions have to be strings like \"^0.1\" or \"=1.2.0\"")
+                                }
+                                val => {
+                                    bail!("Invalid value in dependency table, value: {val:?}")
+                                }
+                            }
                         }
-                        val => {
-                            bail!("Invalid value in dependency table, value: {val:?}")
+                        let mut git_ref = None;
+                        for (key, to_ref) in [
+                            ("branch", GitRef::Branch as fn(String) -> GitRef),
+                            ("tag", GitRef::Tag),
+                            ("rev", GitRef::Rev),
+                        ] {
+                            if let Some(val) = table.get::<_, Option<String>>(key)? {
+                                if git_ref.is_some() || version.is_some() {
+                                    bail!("A dependency can only have one of a version, `branch`, `tag` or `rev`");
+                                }
+                                git_ref = Some(to_ref(val));
+                            }
                         }
+
+                        let origin = origin.context("Dependency is missing its origin")?;
+                        let mut dependency = Dependency::new(&origin, version.as_deref())?;
+                        dependency.git_ref = git_ref;
+                        dependency
                     }
+                };
+
+                dependency.optional = table.get::<_, Option<bool>>("optional")?.unwrap_or(false);
+                if let Some(features) = table.get::<_, Option<Vec<String>>>("features")? {
+                    dependency.features.features = features;
+                }
+                if let Some(default_features) = table.get::<_, Option<bool>>("default_features")? {
+                    dependency.features.default_features = default_features;
                 }
-                let dependency = Dependency::new(&origin, &version.to_string());
                 dependencies.insert(dependency);
             }
         }
 
+        for (feature_name, feature) in &features.features {
+            for dep_name in &feature.deps {
+                let is_optional = dependencies
+                    .iter()
+                    .any(|dep| dep.optional && dep.name().is_ok_and(|name| name == *dep_name));
+                if !is_optional {
+                    bail!(
+                        "Feature `{}` enables `{}`, which is not an optional dependency",
+                        feature_name,
+                        dep_name
+                    );
+                }
+            }
+        }
+
         // version selection
         for std in STANDARDS {
             if c_std_str == std.to_string() {
@@ -158,15 +413,17 @@ impl Config {
                 match key.as_str() {
                     "pre" => {
                         pre_scripts = val
-                            .sequence_values::<String>()
-                            .map(|val| PathBuf::from(val.unwrap()))
-                            .collect()
+                            .sequence_values::<Value>()
+                            .map(|val| Script::parse(val?))
+                            .collect::<anyhow::Result<_>>()
+                            .context("Invalid pre script")?
                     }
                     "post" => {
                         post_scripts = val
-                            .sequence_values::<String>()
-                            .map(|val| PathBuf::from(val.unwrap()))
-                            .collect()
+                            .sequence_values::<Value>()
+                            .map(|val| Script::parse(val?))
+                            .collect::<anyhow::Result<_>>()
+                            .context("Invalid post script")?
                     }
                     key => bail!("Found invalid key: {key}"),
                 }
@@ -189,9 +446,137 @@ impl Config {
             proj_version: proj_version.context("Failed to get project version")?,
             deps: DepManager::new(dependencies),
             proj_type,
-            entry: entry.into(),
+            lib_kind,
+            entry,
+            bins,
+            examples,
             excluded,
+            include_dirs,
+            defines,
+            features,
             scripts,
+            generators,
+            compdb,
+            profiles: profile::parse_profiles(profiles_table)
+                .context("Failed to parse profiles")?,
+            targets: target::parse_targets(targets_table).context("Failed to parse targets")?,
+            test_timeouts,
+            commands,
+            lua: Rc::clone(&lua),
         })
     }
+
+    /// Dependencies that are used with the enabled features.
+    /// Optional dependencies are only used if a feature enables them
+    pub fn active_deps(&self, features: &BTreeSet<String>) -> DepManager {
+        let enabled = self.features.deps(features);
+        DepManager::new(
+            self.deps
+                .deps
+                .iter()
+                .filter(|dep| {
+                    !dep.optional || dep.name().is_ok_and(|name| enabled.contains(name.as_str()))
+                })
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Macros defined with the enabled features
+    pub fn enabled_defines(&self, features: &BTreeSet<String>) -> Vec<String> {
+        let mut defines = self.defines.clone();
+        defines.extend(self.features.defines(features));
+        defines
+    }
+
+    /// Build scripts, generators and custom commands, the code
+    /// that runs unrestricted and needs the project to be trusted
+    pub fn unrestricted_code(&self) -> Vec<String> {
+        let mut code: Vec<String> = self
+            .scripts
+            .iter()
+            .flat_map(|sm| sm.scripts())
+            .map(|script| format!("build script `{}`", script.path.display()))
+            .collect();
+        code.extend(self.generators.iter().map(|generator| {
+            format!(
+                "generator `{}` (`{}`)",
+                generator.name,
+                generator.command.join(" ")
+            )
+        }));
+        code.extend(
+            self.commands
+                .iter()
+                .map(|command| format!("command `{}` (`{}`)", command.name, command.action)),
+        );
+        code
+    }
+
+    /// Look up a target by its triple.
+    /// Targets that aren't in the `Targets` table use the defaults
+    pub fn target(&self, triple: &str) -> Target {
+        self.targets
+            .get(triple)
+            .cloned()
+            .unwrap_or_else(|| Target::new(triple))
+    }
+
+    /// Look up a profile by the name it was selected with
+    pub fn profile(&self, name: &str) -> anyhow::Result<&Profile> {
+        match self.profiles.get(name) {
+            Some(profile) => Ok(profile),
+            None => {
+                let mut names: Vec<&String> = self.profiles.keys().collect();
+                names.sort();
+                bail!(
+                    "Unknown profile `{}`, available profiles: {}",
+                    name,
+                    names
+                        .iter()
+                        .map(|name| format!("`{}`", name))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+        }
+    }
+}
+
+/// Every C file in `dir` is an executable named after the file
+fn discover_bins(dir: &Path) -> Vec<Bin> {
+    let Ok(dir) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    dir.flatten()
+        .map(|file| file.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "c"))
+        .filter_map(|path| {
+            let name = path.file_stem()?.to_string_lossy().to_string();
+            Some(Bin { name, entry: path })
+        })
+        .collect()
+}
+
+/// Read `val` as a list of strings, `kind` and `name` identify
+/// the table `key` belongs to in error messages, like `profile` `release`
+pub fn string_list(kind: &str, name: &str, key: &str, val: Value) -> anyhow::Result<Vec<String>> {
+    match val {
+        Value::Table(table) => table
+            .sequence_values::<String>()
+            .map(|val| {
+                val.context(format!(
+                    "`{}` of {} `{}` has to be a list of strings",
+                    key, kind, name
+                ))
+            })
+            .collect(),
+        val => bail!(
+            "`{}` of {} `{}` has to be a list of strings, found: {:?}",
+            key,
+            kind,
+            name,
+            val
+        ),
+    }
 }
@@ -23,6 +23,25 @@ int main(void) {
 }
 "#;
 
+const LIB_HEADER_LAYOUT: &str = r#"#pragma once
+
+void hello(void);
+"#;
+
+const LIB_FILE_LAYOUT: &str = r#"#include <stdio.h>
+
+void hello(void) {
+    printf("Hello, World!\n");
+}
+"#;
+
+const EXAMPLE_FILE_LAYOUT: &str = r#"#include <{name}.h>
+
+int main(void) {
+    hello();
+}
+"#;
+
 const GITIGNORE_LAYOUT: &str = "build/\n";
 
 impl<'p> Project<'p> {
@@ -50,8 +69,15 @@ impl<'p> Project<'p> {
         // Cfg file
         Self::create_cfg_file(self.root_dir, &self.name, is_lib)?;
 
-        // Main file
-        Self::create_main_file(self.root_dir, is_lib)?;
+        if is_lib {
+            // Library, its public header and an example that uses it
+            self.create_dir("include")?;
+            self.create_dir("examples")?;
+            self.create_lib_files()?;
+        } else {
+            // Main file
+            Self::create_main_file(self.root_dir)?;
+        }
         Ok(())
     }
 
@@ -87,30 +113,20 @@ impl<'p> Project<'p> {
                 "    type = \"{}\",\n",
                 "    compiler = \"{}\",\n",
                 "}}\n",
-                "{}",
                 "\n-- external dependenciess\n",
                 "Dependencies = {{\n",
-                "    -- {{ \"https://github.com/Surtur-Team/surtests\", 0.1 }}\n",
+                "    -- {{ \"https://github.com/Surtur-Team/surtests\", \"^0.1\" }}\n",
                 "}}\n"
             ),
             name,
             if lib { "lib" } else { "bin" },
             DEFAULT_COMPILER,
-            if lib {
-                "\n-- C files that should not be compiled manually (don't have a header)\n-- lib.c is excluded here if your project is a library\nExclude = {\n    \"lib.c\",\n}\n"
-            } else {
-                ""
-            }
         )
     }
 
-    pub fn create_main_file(root_dir: &Path, is_lib: bool) -> anyhow::Result<()> {
-        let mut main_file = File::create(format!(
-            "{}/src/{}.c",
-            root_dir.display(),
-            if is_lib { "lib" } else { "main" }
-        ))
-        .context("Failed to create main file")?;
+    pub fn create_main_file(root_dir: &Path) -> anyhow::Result<()> {
+        let mut main_file = File::create(root_dir.join("src").join("main.c"))
+            .context("Failed to create main file")?;
 
         // write content to main file
         main_file
@@ -118,6 +134,26 @@ impl<'p> Project<'p> {
             .context("Failed to write example code to main.c file")
     }
 
+    /// Create `src/<name>.c`, its header `include/<name>.h`
+    /// and `examples/hello.c` which calls the library
+    fn create_lib_files(&self) -> anyhow::Result<()> {
+        let files = [
+            (format!("src/{}.c", self.name), LIB_FILE_LAYOUT.to_string()),
+            (format!("include/{}.h", self.name), LIB_HEADER_LAYOUT.to_string()),
+            (
+                "examples/hello.c".to_string(),
+                EXAMPLE_FILE_LAYOUT.replace("{name}", &self.name),
+            ),
+        ];
+        for (path, content) in files {
+            let mut file = File::create(self.root_dir.join(&path))
+                .context(format!("Failed to create {}", path))?;
+            file.write_all(content.as_bytes())
+                .context(format!("Failed to write example code to {}", path))?;
+        }
+        Ok(())
+    }
+
     pub fn create_cfg_file(root_dir: &Path, root_name: &str, lib: bool) -> anyhow::Result<()> {
         let mut config_file =
             File::create(root_dir.join("project.lua")).context("Failed to create config gile")?;
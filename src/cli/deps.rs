@@ -8,23 +8,83 @@
 /// Individula dependencies are in the Dependency
 /// struct and store basic information about the
 /// specific dependency
-use std::{collections::HashSet, env, error::Error, fmt::Display, path::PathBuf};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    env,
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
 
-use anyhow::bail;
+use anyhow::{bail, Context};
+use semver::VersionReq;
 
 use crate::{global, util::files::FileHandler};
 
-use super::config::Config;
+use super::{
+    config::{Config, LibKind},
+    features::FeatureRequest,
+    lock::{LockEntry, Lockfile},
+};
+
+/// Where dependencies that are added by name are hosted
+pub const DEFAULT_ORIGIN: &str = "https://github.com/Surtur-Team";
 
 #[derive(Debug, Default)]
 pub struct DepManager {
     pub deps: HashSet<Dependency>,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Dependency {
-    pub _version: String,
-    pub origin: String,
+    /// Semver requirement that is resolved against the
+    /// git tags of the dependency. If there is none,
+    /// the default branch is used
+    pub version: Option<VersionReq>,
+    /// Branch, tag or revision a git dependency is pinned to
+    pub git_ref: Option<GitRef>,
+    pub source: Source,
+    /// Only used if a feature of the project enables it
+    pub optional: bool,
+    /// Features of the dependency that are enabled
+    pub features: FeatureRequest,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum GitRef {
+    Branch(String),
+    Tag(String),
+    Rev(String),
+}
+
+impl Display for GitRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GitRef::Branch(branch) => write!(f, "branch:{}", branch),
+            GitRef::Tag(tag) => write!(f, "tag:{}", tag),
+            GitRef::Rev(rev) => write!(f, "rev:{}", rev),
+        }
+    }
+}
+
+/// Where the code of a dependency comes from
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Source {
+    /// Git repository that is cloned into the
+    /// global dependency directory
+    Git(String),
+    /// Directory on the local file system,
+    /// the library is built in place
+    Path(PathBuf),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Git(origin) => f.write_str(origin),
+            Source::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
 }
 
 impl DepManager {
@@ -32,44 +92,453 @@ impl DepManager {
         Self { deps: dependencies }
     }
 
-    /// Downloads the dependency into your projects depndency directoy
-    pub fn download_deps(&self, forced: bool) -> anyhow::Result<()> {
-        for dep in &self.deps {
-            global::download_dep(dep, forced)?;
+    /// Downloads all dependencies, including the dependencies of
+    /// dependencies, into the global dependency directory and
+    /// returns the lockfile with the resolved commits.
+    /// Optional dependencies are downloaded as well, so enabling
+    /// a feature doesn't need an update
+    pub fn download_deps(&self, forced: bool) -> anyhow::Result<Lockfile> {
+        let mut entries = Vec::new();
+        self.resolve(true, |dep| {
+            // Local dependencies are used as they are
+            let Some(origin) = dep.origin() else {
+                return Ok(());
+            };
+            let resolved = global::download_dep(dep, forced)?;
+            entries.push(LockEntry {
+                name: dep.name()?,
+                origin: origin.to_string(),
+                req: dep.version.as_ref().map(|req| req.to_string()),
+                git_ref: dep.git_ref.as_ref().map(|git_ref| git_ref.to_string()),
+                tag: resolved.tag,
+                commit: resolved.commit,
+            });
+            Ok(())
+        })?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Lockfile { entries })
+    }
+
+    /// Build the full dependency graph by reading the config
+    /// of every dependency. `visit` is called for every
+    /// dependency before its config is read, so it can
+    /// download or check out the dependency first.
+    /// With `all_features`, every feature of every dependency
+    /// is enabled instead of the requested ones
+    pub fn resolve(
+        &self,
+        all_features: bool,
+        mut visit: impl FnMut(&Dependency) -> anyhow::Result<()>,
+    ) -> anyhow::Result<DepGraph> {
+        let mut resolver = Resolver {
+            nodes: Vec::new(),
+            configs: Vec::new(),
+            index: HashMap::new(),
+            dependents: Vec::new(),
+            stack: Vec::new(),
+            all_features,
+            visit: &mut visit,
+        };
+        let mut roots = Vec::new();
+        for dep in sorted(&self.deps)? {
+            roots.push(resolver.visit_dep(dep)?);
+        }
+        DepGraph::in_build_order(resolver.nodes, roots)
+    }
+}
+
+/// All dependencies of a project, including
+/// the dependencies of dependencies
+#[derive(Debug, Default)]
+pub struct DepGraph {
+    /// Nodes in build order, every node comes
+    /// after all of its dependencies
+    pub nodes: Vec<DepNode>,
+    /// Indices of the direct dependencies of the project
+    pub roots: Vec<usize>,
+}
+
+#[derive(Debug)]
+pub struct DepNode {
+    pub dep: Dependency,
+    pub name: String,
+    pub location: PathBuf,
+    pub version: String,
+    pub lib_kind: LibKind,
+    pub excluded: HashSet<PathBuf>,
+    /// Enabled features, the union of the features
+    /// requested by all projects that depend on it
+    pub features: BTreeSet<String>,
+    /// Macros of the dependency with its enabled features
+    pub defines: Vec<String>,
+    /// Public include directories, passed to the
+    /// projects that depend on this one
+    pub include_dirs: Vec<PathBuf>,
+    /// Indices of the direct dependencies of this node
+    pub deps: Vec<usize>,
+    /// Whether the node is linked into a shared library, its own or
+    /// one that depends on it, and has to be position independent
+    pub pic: bool,
+}
+
+impl DepGraph {
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Order the nodes so every node comes after its dependencies.
+    /// Enabling features of a node that was already resolved can
+    /// give it dependencies that were resolved after it
+    fn in_build_order(nodes: Vec<DepNode>, roots: Vec<usize>) -> anyhow::Result<Self> {
+        fn place(
+            idx: usize,
+            nodes: &[DepNode],
+            order: &mut Vec<usize>,
+            placed: &mut [Option<usize>],
+            stack: &mut Vec<usize>,
+        ) -> anyhow::Result<()> {
+            if placed[idx].is_some() {
+                return Ok(());
+            }
+            if let Some(pos) = stack.iter().position(|elem| *elem == idx) {
+                let mut cycle: Vec<&str> = stack[pos..]
+                    .iter()
+                    .map(|elem| nodes[*elem].name.as_str())
+                    .collect();
+                cycle.push(&nodes[idx].name);
+                bail!("Found dependency cycle: {}", cycle.join(" -> "));
+            }
+            stack.push(idx);
+            for &dep in &nodes[idx].deps {
+                place(dep, nodes, order, placed, stack)?;
+            }
+            stack.pop();
+            placed[idx] = Some(order.len());
+            order.push(idx);
+            Ok(())
+        }
+
+        let mut order = Vec::new();
+        let mut placed = vec![None; nodes.len()];
+        for &root in &roots {
+            place(root, &nodes, &mut order, &mut placed, &mut Vec::new())?;
+        }
+
+        let new_idx = |idx: usize| placed[idx].expect("Every node is reachable from a root");
+        let mut nodes: Vec<Option<DepNode>> = nodes.into_iter().map(Some).collect();
+        let nodes = order
+            .iter()
+            .map(|&idx| {
+                let mut node = nodes[idx].take().expect("Every node is placed once");
+                node.deps = node.deps.iter().map(|&dep| new_idx(dep)).collect();
+                node
+            })
+            .collect();
+        Ok(Self {
+            nodes,
+            roots: roots.into_iter().map(new_idx).collect(),
+        })
+    }
+
+    /// Group the nodes into levels that only depend on nodes
+    /// of earlier levels. All nodes of a level can be built
+    /// at the same time
+    pub fn levels(&self) -> Vec<Vec<&DepNode>> {
+        let mut depths = vec![0; self.nodes.len()];
+        let mut levels: Vec<Vec<&DepNode>> = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let depth = node
+                .deps
+                .iter()
+                .map(|dep| depths[*dep] + 1)
+                .max()
+                .unwrap_or(0);
+            depths[i] = depth;
+            if levels.len() <= depth {
+                levels.resize_with(depth + 1, Vec::new);
+            }
+            levels[depth].push(node);
+        }
+        levels
+    }
+
+    /// Nodes that `roots` depend on, directly or not, in the order they
+    /// have to be passed to the linker. Every node comes before its dependencies
+    pub fn link_order(&self, roots: &[usize]) -> impl Iterator<Item = &DepNode> {
+        let mut used = vec![false; self.nodes.len()];
+        for &root in roots {
+            used[root] = true;
+        }
+        // Dependencies come before the nodes that depend on them
+        for i in (0..self.nodes.len()).rev() {
+            if used[i] {
+                for &dep in &self.nodes[i].deps {
+                    used[dep] = true;
+                }
+            }
+        }
+        self.nodes
+            .iter()
+            .zip(used)
+            .rev()
+            .filter_map(|(node, used)| used.then_some(node))
+    }
+
+    /// Mark the nodes that are linked into a shared library as
+    /// position independent, `shared_root` is whether the project
+    /// itself is a shared library that links its dependencies
+    pub fn mark_pic(&mut self, shared_root: bool) {
+        if shared_root {
+            for &root in &self.roots {
+                self.nodes[root].pic = true;
+            }
+        }
+        for i in (0..self.nodes.len()).rev() {
+            let node = &mut self.nodes[i];
+            node.pic |= node.lib_kind.is_shared();
+            if node.pic {
+                for dep in node.deps.clone() {
+                    self.nodes[dep].pic = true;
+                }
+            }
+        }
+    }
+}
+
+struct Resolver<'r> {
+    nodes: Vec<DepNode>,
+    /// Config of every node, used to enable more
+    /// features of nodes that were already resolved
+    configs: Vec<Config>,
+    /// Index of every resolved node by its name
+    index: HashMap<String, usize>,
+    /// Project that first required every node, for errors
+    /// about requirements of the same dependency that conflict
+    dependents: Vec<String>,
+    /// Names of the dependencies that are currently
+    /// being resolved, used to detect cycles
+    stack: Vec<String>,
+    all_features: bool,
+    visit: &'r mut dyn FnMut(&Dependency) -> anyhow::Result<()>,
+}
+
+impl Resolver<'_> {
+    fn visit_dep(&mut self, dep: Dependency) -> anyhow::Result<usize> {
+        let name = dep.name()?;
+
+        if let Some(pos) = self.stack.iter().position(|elem| *elem == name) {
+            let mut cycle = self.stack[pos..].to_vec();
+            cycle.push(name);
+            bail!("Found dependency cycle: {}", cycle.join(" -> "));
+        }
+        let request = match self.all_features {
+            true => FeatureRequest::all(),
+            false => dep.features.clone(),
+        };
+
+        if let Some(&idx) = self.index.get(&name) {
+            let node = &self.nodes[idx];
+            if node.dep.source != dep.source {
+                bail!(
+                    "Dependency `{}` is required from two different sources: {} and {}",
+                    name,
+                    node.dep.source,
+                    dep.source
+                );
+            }
+            // The dependency is checked out for the first requirement,
+            // other dependents have to be fine with that commit
+            let same_req = node.dep.version == dep.version && node.dep.git_ref == dep.git_ref;
+            if !same_req && dep.origin().is_some() && !global::is_satisfied(&dep)? {
+                bail!(
+                    "Dependency `{}` is required as {} by {} and as {} by {}, the version that is used doesn't satisfy both",
+                    name,
+                    requirement(&node.dep),
+                    self.dependents[idx],
+                    requirement(&dep),
+                    self.dependent()
+                );
+            }
+            // A dependency is only built once, with the
+            // features of all projects that depend on it
+            let features = self.configs[idx]
+                .features
+                .resolve(&request)
+                .context(format!("Invalid features for dependency `{}`", name))?;
+            if !features.is_subset(&node.features) {
+                let features = features.union(&node.features).cloned().collect();
+                self.enable_features(idx, features)?;
+            }
+            return Ok(idx);
+        }
+
+        (self.visit)(&dep)?;
+        let cfg = dep.config().context(format!(
+            "Failed to read the config of dependency `{}`, try running `surtur update`",
+            name
+        ))?;
+        let features = cfg
+            .features
+            .resolve(&request)
+            .context(format!("Invalid features for dependency `{}`", name))?;
+
+        self.stack.push(name.clone());
+        let mut deps = Vec::new();
+        for child in sorted(&cfg.active_deps(&features).deps)? {
+            deps.push(self.visit_dep(child)?);
+        }
+        self.stack.pop();
+
+        let idx = self.nodes.len();
+        self.nodes.push(DepNode {
+            location: dep.location()?,
+            dep,
+            name: name.clone(),
+            version: cfg.proj_version.clone(),
+            lib_kind: cfg.lib_kind,
+            excluded: cfg.excluded.clone(),
+            defines: cfg.enabled_defines(&features),
+            features,
+            include_dirs: cfg.include_dirs.clone(),
+            deps,
+            pic: false,
+        });
+        self.configs.push(cfg);
+        self.dependents.push(self.dependent());
+        self.index.insert(name, idx);
+        Ok(idx)
+    }
+
+    /// The project whose dependencies are being resolved
+    fn dependent(&self) -> String {
+        match self.stack.last() {
+            Some(name) => format!("`{}`", name),
+            None => "the project".into(),
+        }
+    }
+
+    /// Enable more features of a node that was already
+    /// resolved, including the dependencies they enable
+    fn enable_features(&mut self, idx: usize, features: BTreeSet<String>) -> anyhow::Result<()> {
+        let cfg = &self.configs[idx];
+        let children = sorted(&cfg.active_deps(&features).deps)?;
+        self.nodes[idx].defines = cfg.enabled_defines(&features);
+        self.nodes[idx].features = features;
+
+        self.stack.push(self.nodes[idx].name.clone());
+        for child in children {
+            let child = self.visit_dep(child)?;
+            if !self.nodes[idx].deps.contains(&child) {
+                self.nodes[idx].deps.push(child);
+            }
         }
+        self.stack.pop();
         Ok(())
     }
 }
 
+/// The version, branch, tag or revision a dependency requires
+fn requirement(dep: &Dependency) -> String {
+    match (&dep.version, &dep.git_ref) {
+        (Some(req), _) => format!("`{}`", req),
+        (None, Some(git_ref)) => format!("`{}`", git_ref),
+        (None, None) => "the default branch".into(),
+    }
+}
+
+/// Dependencies sorted by name, so the graph is
+/// always resolved in the same order
+fn sorted(deps: &HashSet<Dependency>) -> anyhow::Result<Vec<Dependency>> {
+    let mut deps: Vec<(String, Dependency)> = deps
+        .iter()
+        .map(|dep| Ok((dep.name()?, dep.clone())))
+        .collect::<anyhow::Result<_>>()?;
+    deps.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(deps.into_iter().map(|(_, dep)| dep).collect())
+}
+
+/// Name of a dependency by its origin or path, which is the last
+/// component without `.git`. For origins like `file:///repos/name/.git`
+/// it's the component before `.git`. Empty for origins without a name
+pub fn dep_name(origin: &str) -> &str {
+    let path = origin.split_once("://").map_or(origin, |(_, path)| path);
+    let mut components = path
+        .split(['/', ':'])
+        .filter(|component| !component.is_empty())
+        .rev();
+    let name = match components.next() {
+        Some(".git") => components.next(),
+        name => name,
+    }
+    .unwrap_or("");
+    name.strip_suffix(".git").unwrap_or(name)
+}
+
 impl Dependency {
-    pub fn new(origin: &str, version: &str) -> Self {
-        let origin = match &origin[origin.len() - 4..] {
-            ".git" => origin.to_string(),
-            _ => {
-                let mut origin = origin.to_string();
-                origin.push_str(".git");
-                origin
-            }
+    pub fn new(origin: &str, version: Option<&str>) -> anyhow::Result<Self> {
+        let version = match version {
+            Some(version) => Some(
+                VersionReq::parse(version)
+                    .context(format!("Invalid version requirement: {:?}", version))?,
+            ),
+            None => None,
         };
+        Ok(Self {
+            version,
+            git_ref: None,
+            // Passed to git as it is, local repositories have no `.git` suffix
+            source: Source::Git(origin.to_string()),
+            optional: false,
+            features: FeatureRequest::default(),
+        })
+    }
+
+    /// A dependency on a directory, relative
+    /// paths start at the project's root directory
+    pub fn local(root_dir: &Path, path: &str) -> Self {
+        let path = root_dir.join(path);
         Self {
-            _version: version.into(),
-            origin,
+            version: None,
+            git_ref: None,
+            source: Source::Path(path.canonicalize().unwrap_or(path)),
+            optional: false,
+            features: FeatureRequest::default(),
+        }
+    }
+
+    /// The git origin, if this is a git dependency
+    #[inline]
+    pub fn origin(&self) -> Option<&str> {
+        match &self.source {
+            Source::Git(origin) => Some(origin),
+            Source::Path(_) => None,
         }
     }
 
     pub fn name(&self) -> anyhow::Result<String> {
-        let split_path: Vec<&str> = self.origin.split('/').collect();
-        let name = match split_path.last() {
-            Some(name) => name.to_string(),
-            None => bail!("Invalid origin {}", self.origin),
-        };
-        Ok(name[..name.len() - 4].into())
+        match &self.source {
+            Source::Git(origin) => match dep_name(origin) {
+                "" => bail!("Invalid origin {}", origin),
+                name => Ok(name.into()),
+            },
+            Source::Path(path) => Ok(path
+                .file_name()
+                .context(format!("Invalid dependency path {}", path.display()))?
+                .to_string_lossy()
+                .to_string()),
+        }
     }
 
     pub fn location(&self) -> anyhow::Result<PathBuf> {
-        let surtur_home = PathBuf::from(env::var(global::SURTUR_HOME)?);
-        let deps_path = surtur_home.join("deps");
-        Ok(deps_path.join(self.name()?))
+        match &self.source {
+            Source::Git(_) => {
+                let surtur_home = PathBuf::from(env::var(global::SURTUR_HOME)?);
+                let deps_path = surtur_home.join("deps");
+                Ok(deps_path.join(self.name()?))
+            }
+            Source::Path(path) => Ok(path.clone()),
+        }
     }
 
     pub fn config(&self) -> anyhow::Result<Config> {
@@ -90,3 +559,123 @@ impl Display for NoDepError {
 }
 
 impl Error for NoDepError {}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use git2::{Repository, Signature};
+
+    use super::*;
+
+    #[test]
+    fn names_of_origins() {
+        assert_eq!(
+            dep_name("https://github.com/Surtur-Team/surtests"),
+            "surtests"
+        );
+        assert_eq!(
+            dep_name("https://github.com/Surtur-Team/surtests.git"),
+            "surtests"
+        );
+        assert_eq!(dep_name("file:///repos/libz/"), "libz");
+        assert_eq!(dep_name("file:///repos/libz/.git"), "libz");
+        assert_eq!(
+            dep_name("git@github.com:Surtur-Team/surtests.git"),
+            "surtests"
+        );
+        assert_eq!(dep_name("a:b"), "b");
+        assert_eq!(dep_name("/.git"), "");
+        assert!(Dependency::new("file:///.git", None)
+            .unwrap()
+            .name()
+            .is_err());
+    }
+
+    /// Create a library repository with a `v0.1.0` tag and a
+    /// `dev` branch that is one commit ahead, returns the commits
+    fn library_repo(dir: &Path) -> (String, String) {
+        let repo = Repository::init(dir).unwrap();
+        let sig = Signature::now("surtur", "surtur@example.com").unwrap();
+        let commit = |content: &str, parents: &[&git2::Commit]| {
+            fs::write(
+                dir.join("project.lua"),
+                format!("Name = \"libz\"\nProps = {{ version = \"{}\" }}\n", content),
+            )
+            .unwrap();
+            let mut index = repo.index().unwrap();
+            index.add_path(Path::new("project.lua")).unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let oid = repo
+                .commit(None, &sig, &sig, content, &tree, parents)
+                .unwrap();
+            repo.find_commit(oid).unwrap()
+        };
+        let tagged = commit("0.1.0", &[]);
+        let dev = commit("0.2.0", &[&tagged]);
+        repo.branch("main", &tagged, true).unwrap();
+        repo.set_head("refs/heads/main").unwrap();
+        repo.branch("dev", &dev, true).unwrap();
+        repo.tag_lightweight("v0.1.0", tagged.as_object(), false)
+            .unwrap();
+        (tagged.id().to_string(), dev.id().to_string())
+    }
+
+    #[test]
+    fn download_local_repositories() {
+        let tmp = env::temp_dir().join(format!("surtur-deps-{}", process::id()));
+        let _ = fs::remove_dir_all(&tmp);
+        let repo_dir = tmp.join("repos").join("libz");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::create_dir_all(tmp.join("home").join("deps")).unwrap();
+        env::set_var(global::SURTUR_HOME, tmp.join("home"));
+        let (tagged, dev) = library_repo(&repo_dir);
+
+        let origin = format!("file://{}", repo_dir.display());
+        let mut branch = Dependency::new(&origin, None).unwrap();
+        branch.git_ref = Some(GitRef::Branch("dev".into()));
+        let lock = DepManager::new(HashSet::from([branch]))
+            .download_deps(false)
+            .unwrap();
+        assert_eq!(lock.entries.len(), 1);
+        assert_eq!(lock.entries[0].name, "libz");
+        assert_eq!(lock.entries[0].origin, origin);
+        assert_eq!(lock.entries[0].commit, dev);
+
+        // The repository itself as the origin, the name is the directory before `.git`
+        let origin = format!("file://{}/.git", repo_dir.display());
+        let version = Dependency::new(&origin, Some("^0.1")).unwrap();
+        let lock = DepManager::new(HashSet::from([version]))
+            .download_deps(false)
+            .unwrap();
+        assert_eq!(lock.entries[0].name, "libz");
+        assert_eq!(lock.entries[0].tag.as_deref(), Some("v0.1.0"));
+        assert_eq!(lock.entries[0].commit, tagged);
+
+        // `mid` depends on libz too, its requirement has to fit
+        // the version the project's own requirement resolved to
+        let mid_dir = tmp.join("mid");
+        fs::create_dir_all(&mid_dir).unwrap();
+        let diamond = |mid_req: &str| {
+            fs::write(
+                mid_dir.join("project.lua"),
+                format!(
+                    "Name = \"mid\"\nProps = {{ version = \"0.1.0\" }}\nDependencies = {{ {{ \"{}\", {} }} }}\n",
+                    origin, mid_req
+                ),
+            )
+            .unwrap();
+            DepManager::new(HashSet::from([
+                Dependency::new(&origin, Some("^0.1")).unwrap(),
+                Dependency::local(&tmp, "mid"),
+            ]))
+            .download_deps(false)
+        };
+        diamond("tag = \"v0.1.0\"").unwrap();
+        let err = diamond("branch = \"dev\"").unwrap_err().to_string();
+        assert!(err.contains("`^0.1` by the project"), "{}", err);
+        assert!(err.contains("`branch:dev` by `mid`"), "{}", err);
+
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+}
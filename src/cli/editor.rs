@@ -0,0 +1,458 @@
+//! Editing of the project's lua config file.
+//! Instead of regenerating the file, the
+//! source is tokenized and only the affected
+//! parts are changed, so comments and the
+//! formatting of the user are kept
+
+use anyhow::{bail, Context};
+
+use crate::util;
+
+use super::deps::dep_name;
+
+#[derive(Debug, PartialEq)]
+enum Tok {
+    Ident(String),
+    Str(String),
+    Sym(char),
+}
+
+#[derive(Debug)]
+struct Token {
+    tok: Tok,
+    start: usize,
+    end: usize,
+}
+
+/// Length of the opening long bracket (`[[`, `[==[`) at `pos`
+fn long_bracket(src: &[u8], pos: usize) -> Option<usize> {
+    if src.get(pos) != Some(&b'[') {
+        return None;
+    }
+    let mut len = 1;
+    while src.get(pos + len) == Some(&b'=') {
+        len += 1;
+    }
+    match src.get(pos + len) {
+        Some(b'[') => Some(len + 1),
+        _ => None,
+    }
+}
+
+/// End of a long string or comment that was opened with a bracket of `len`
+fn long_bracket_end(src: &[u8], pos: usize, len: usize) -> usize {
+    let mut close = vec![b']'];
+    close.extend(std::iter::repeat_n(b'=', len - 2));
+    close.push(b']');
+    src[pos..]
+        .windows(close.len())
+        .position(|window| window == close.as_slice())
+        .map(|end| pos + end + close.len())
+        .unwrap_or(src.len())
+}
+
+/// Split lua source into the tokens needed to find tables.
+/// Comments are skipped, numbers and operators other
+/// than the table syntax are returned as symbols
+fn tokenize(src: &str) -> Vec<Token> {
+    let bytes = src.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let start = pos;
+        match bytes[pos] {
+            b'-' if bytes.get(pos + 1) == Some(&b'-') => {
+                pos += 2;
+                match long_bracket(bytes, pos) {
+                    Some(len) => pos = long_bracket_end(bytes, pos + len, len),
+                    None => {
+                        while pos < bytes.len() && bytes[pos] != b'\n' {
+                            pos += 1;
+                        }
+                    }
+                }
+            }
+            quote @ (b'"' | b'\'') => {
+                pos += 1;
+                let mut content = Vec::new();
+                while pos < bytes.len() && bytes[pos] != quote {
+                    if bytes[pos] == b'\\' && pos + 1 < bytes.len() {
+                        pos += 1;
+                        content.push(match bytes[pos] {
+                            b'n' => b'\n',
+                            b't' => b'\t',
+                            b'r' => b'\r',
+                            c => c,
+                        });
+                    } else {
+                        content.push(bytes[pos]);
+                    }
+                    pos += 1;
+                }
+                pos += 1;
+                tokens.push(Token {
+                    tok: Tok::Str(String::from_utf8_lossy(&content).to_string()),
+                    start,
+                    end: pos.min(bytes.len()),
+                });
+            }
+            b'[' if long_bracket(bytes, pos).is_some() => {
+                let len = long_bracket(bytes, pos).unwrap();
+                pos = long_bracket_end(bytes, pos + len, len);
+                let content_end = pos.saturating_sub(len).max(start + len);
+                tokens.push(Token {
+                    tok: Tok::Str(src[start + len..content_end].to_string()),
+                    start,
+                    end: pos,
+                });
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                while pos < bytes.len()
+                    && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_')
+                {
+                    pos += 1;
+                }
+                tokens.push(Token {
+                    tok: Tok::Ident(src[start..pos].to_string()),
+                    start,
+                    end: pos,
+                });
+            }
+            c if c.is_ascii_whitespace() => pos += 1,
+            _ => {
+                let c = src[pos..].chars().next().unwrap();
+                pos += c.len_utf8();
+                tokens.push(Token {
+                    tok: Tok::Sym(c),
+                    start,
+                    end: pos,
+                });
+            }
+        }
+    }
+    tokens
+}
+
+/// Index of the token that closes the table opened at `open`
+fn matching_close(tokens: &[Token], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        match token.tok {
+            Tok::Sym('{') => depth += 1,
+            Tok::Sym('}') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Token indices of the braces of a global table like `Name = { ... }`
+fn find_table(tokens: &[Token], name: &str) -> Option<(usize, usize)> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match &token.tok {
+            Tok::Sym('{') => depth += 1,
+            Tok::Sym('}') => depth -= 1,
+            Tok::Ident(ident)
+                if depth == 0
+                    && ident == name
+                    && tokens.get(i + 1).map(|t| &t.tok) == Some(&Tok::Sym('='))
+                    && tokens.get(i + 2).map(|t| &t.tok) == Some(&Tok::Sym('{')) =>
+            {
+                return Some((i + 2, matching_close(tokens, i + 2)?));
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// A table inside of another table
+struct Entry {
+    open: usize,
+    close: usize,
+    /// The first positional string of the entry or its `path`,
+    /// other strings like a `branch` or features are not the origin
+    origin: Option<String>,
+}
+
+fn entries(tokens: &[Token], open: usize, close: usize) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut i = open + 1;
+    while i < close {
+        if tokens[i].tok == Tok::Sym('{') {
+            let entry_close = matching_close(tokens, i).unwrap_or(close);
+            entries.push(Entry {
+                open: i,
+                close: entry_close,
+                origin: entry_origin(tokens, i, entry_close),
+            });
+            i = entry_close;
+        }
+        i += 1;
+    }
+    entries
+}
+
+/// Origin of the dependency entry between the braces `open` and `close`
+fn entry_origin(tokens: &[Token], open: usize, close: usize) -> Option<String> {
+    let mut depth = 0;
+    let mut positional = None;
+    for i in open + 1..close {
+        match &tokens[i].tok {
+            Tok::Sym('{') => depth += 1,
+            Tok::Sym('}') => depth -= 1,
+            Tok::Ident(key)
+                if depth == 0 && key == "path" && tokens[i + 1].tok == Tok::Sym('=') =>
+            {
+                if let Some(Tok::Str(path)) = tokens.get(i + 2).map(|t| &t.tok) {
+                    return Some(path.clone());
+                }
+            }
+            Tok::Str(val)
+                if depth == 0
+                    && positional.is_none()
+                    && matches!(tokens[i - 1].tok, Tok::Sym('{' | ',' | ';'))
+                    && matches!(tokens[i + 1].tok, Tok::Sym('}' | ',' | ';')) =>
+            {
+                positional = Some(val.clone());
+            }
+            _ => (),
+        }
+    }
+    positional
+}
+
+#[inline(always)]
+fn line_start(src: &str, pos: usize) -> usize {
+    src[..pos].rfind('\n').map(|pos| pos + 1).unwrap_or(0)
+}
+
+/// Add a dependency entry to the `Dependencies` table.
+/// If the table doesn't exist, it is appended to the file
+pub fn add_dep(src: &str, origin: &str, version: Option<&str>) -> String {
+    let mut entry = format!("{{ {}", util::lua_str(origin));
+    if let Some(version) = version {
+        entry.push_str(&format!(", {}", util::lua_str(version)));
+    }
+    entry.push_str(" }");
+
+    let tokens = tokenize(src);
+    let (open, close) = match find_table(&tokens, "Dependencies") {
+        Some(table) => table,
+        None => {
+            let mut out = src.to_string();
+            if !out.is_empty() && !out.ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str(&format!(
+                "\n-- external dependencies\nDependencies = {{\n    {},\n}}\n",
+                entry
+            ));
+            return out;
+        }
+    };
+
+    let close_pos = tokens[close].start;
+    let close_line = line_start(src, close_pos);
+    let line_indent: String = src[close_line..]
+        .chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .collect();
+    let brace_on_own_line = src[close_line..close_pos].trim().is_empty();
+
+    let indent = match entries(&tokens, open, close).first() {
+        Some(first)
+            if src[line_start(src, tokens[first.open].start)..tokens[first.open].start]
+                .trim()
+                .is_empty() =>
+        {
+            let start = tokens[first.open].start;
+            src[line_start(src, start)..start].to_string()
+        }
+        _ => format!("{}    ", line_indent),
+    };
+
+    // The previous value needs a seperator before the new entry
+    let last = &tokens[close - 1];
+    let sep = match last.tok {
+        Tok::Sym(',' | ';' | '{') => "",
+        _ => ",",
+    };
+
+    if brace_on_own_line {
+        format!(
+            "{}{}{}{}{},\n{}",
+            &src[..last.end],
+            sep,
+            &src[last.end..close_line],
+            indent,
+            entry,
+            &src[close_line..]
+        )
+    } else {
+        format!(
+            "{}{}\n{}{},\n{}{}",
+            &src[..last.end],
+            sep,
+            indent,
+            entry,
+            line_indent,
+            &src[close_pos..]
+        )
+    }
+}
+
+/// Remove the dependency with the specified name
+/// from the `Dependencies` table
+pub fn remove_dep(src: &str, name: &str) -> anyhow::Result<String> {
+    let tokens = tokenize(src);
+    let (open, close) =
+        find_table(&tokens, "Dependencies").context("project.lua has no `Dependencies` table")?;
+
+    let entry = entries(&tokens, open, close).into_iter().find(|entry| {
+        entry
+            .origin
+            .as_deref()
+            .is_some_and(|origin| dep_name(origin) == name)
+    });
+    let entry = match entry {
+        Some(entry) => entry,
+        None => bail!("Dependency `{}` is not in project.lua", name),
+    };
+
+    let mut start = tokens[entry.open].start;
+    let mut end = tokens[entry.close].end;
+    if let Some(Tok::Sym(',' | ';')) = tokens.get(entry.close + 1).map(|t| &t.tok) {
+        end = tokens[entry.close + 1].end;
+    }
+
+    // Remove the whole line if the entry was the only thing
+    // on it, together with a comment at the end of the line
+    let line = line_start(src, start);
+    let line_end = src[end..].find('\n').map(|pos| end + pos + 1);
+    if let Some(line_end) = line_end {
+        let rest = src[end..line_end].trim();
+        let is_comment = rest.starts_with("--") && long_bracket(rest.as_bytes(), 2).is_none();
+        if src[line..start].trim().is_empty() && (rest.is_empty() || is_comment) {
+            start = line;
+            end = line_end;
+        }
+    }
+
+    Ok(format!("{}{}", &src[..start], &src[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"Name = "app"
+
+Props = {
+    version = "0.1.0", -- bumped on release
+}
+
+-- external dependencies
+Dependencies = {
+    { "https://example.com/bar", branch = "foo" }, -- pinned until 1.0
+    { "https://example.com/foo", "^1.0", features = { "bar" } },
+    { path = "../baz" },
+}
+"#;
+
+    #[test]
+    fn add_to_existing_table() {
+        let src = add_dep(CONFIG, "https://example.com/qux", Some("^0.2"));
+        assert_eq!(
+            src,
+            CONFIG.replace(
+                "    { path = \"../baz\" },\n",
+                "    { path = \"../baz\" },\n    { \"https://example.com/qux\", \"^0.2\" },\n"
+            )
+        );
+    }
+
+    #[test]
+    fn add_without_table() {
+        let src = add_dep("Name = \"app\"", "https://example.com/qux", None);
+        assert_eq!(
+            src,
+            "Name = \"app\"\n\n-- external dependencies\nDependencies = {\n    { \"https://example.com/qux\" },\n}\n"
+        );
+    }
+
+    #[test]
+    fn add_to_inline_table() {
+        let src = add_dep(
+            "Dependencies = { { \"https://example.com/bar\" } }\n",
+            "https://example.com/qux",
+            None,
+        );
+        assert_eq!(
+            src,
+            "Dependencies = { { \"https://example.com/bar\" },\n    { \"https://example.com/qux\" },\n}\n"
+        );
+    }
+
+    #[test]
+    fn add_ignores_tables_in_comments() {
+        let src = "--[[ Dependencies = { } ]]\nDependencies = {\n}\n";
+        assert_eq!(
+            add_dep(src, "https://example.com/qux", None),
+            "--[[ Dependencies = { } ]]\nDependencies = {\n    { \"https://example.com/qux\" },\n}\n"
+        );
+    }
+
+    #[test]
+    fn remove_matches_origin_only() {
+        // `foo` is the branch of `bar` and a feature of `foo`, only the origin counts
+        let src = remove_dep(CONFIG, "foo").unwrap();
+        assert_eq!(
+            src,
+            CONFIG.replace(
+                "    { \"https://example.com/foo\", \"^1.0\", features = { \"bar\" } },\n",
+                ""
+            )
+        );
+    }
+
+    #[test]
+    fn remove_with_trailing_comment() {
+        let src = remove_dep(CONFIG, "bar").unwrap();
+        assert_eq!(
+            src,
+            CONFIG.replace(
+                "    { \"https://example.com/bar\", branch = \"foo\" }, -- pinned until 1.0\n",
+                ""
+            )
+        );
+    }
+
+    #[test]
+    fn remove_path_dep() {
+        let src = remove_dep(CONFIG, "baz").unwrap();
+        assert_eq!(src, CONFIG.replace("    { path = \"../baz\" },\n", ""));
+    }
+
+    #[test]
+    fn remove_from_inline_table() {
+        let src =
+            "Dependencies = { { \"https://example.com/a.git\" }, { \"https://example.com/b\" } }\n";
+        assert_eq!(
+            remove_dep(src, "a").unwrap(),
+            "Dependencies = {  { \"https://example.com/b\" } }\n"
+        );
+    }
+
+    #[test]
+    fn remove_missing_dep() {
+        assert!(remove_dep(CONFIG, "qux").is_err());
+        assert!(remove_dep("Name = \"app\"\n", "foo").is_err());
+    }
+}
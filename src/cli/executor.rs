@@ -2,32 +2,207 @@
 //! the Compiler for easily running and building
 //! everything
 
-use std::{env, fs, iter::Product, path::{Path, PathBuf}, process::Command};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+use colored::Colorize;
 
 use crate::{
     cli::Cli,
-    util::{self, MISSING_CFG},
+    global,
+    util::{self, jobs, MISSING_CFG},
 };
 
 use super::{
-    compiler::{CompileCtx, Compiler},
-    config::ProjType,
+    codegen, compdb,
+    compiler::{self, CompileCtx, Compiler},
+    config::{Bin, Config, ProjType},
+    deps::{DepGraph, DepManager},
+    features::{self, FeatureRequest},
+    harness::{self, Timeouts},
+    lock::{Lockfile, LOCK_FILE},
+    profile::DEV_PROFILE,
+    report::{self, Format},
+    scripts::{Directives, ScriptEnv},
+    target::Target,
+    trust,
 };
 
-pub fn run_c(cli: Cli, enable_dbg: bool, args: Option<Vec<&String>>) -> anyhow::Result<()> {
+/// Options shared by all commands that build the project
+pub struct BuildOpts {
+    /// Name of the profile from the `Profiles` table
+    pub profile: String,
+    /// Triple of the target to cross compile for
+    pub target: Option<String>,
+    pub features: FeatureRequest,
+    /// Executable selected with `--bin`
+    pub bin: Option<String>,
+    /// Example selected with `--example`
+    pub example: Option<String>,
+    /// Build all examples
+    pub examples: bool,
+    pub jobs: usize,
+    /// Fail instead of warning if project.lock is out of date
+    pub locked: bool,
+    /// Run build scripts even if their inputs didn't change
+    pub rerun_scripts: bool,
+}
+
+/// Options of `surtur test`
+pub struct TestOpts {
+    /// Only run tests whose name contains the filter
+    pub filter: Option<String>,
+    /// Format of the output, or of the report if there is one
+    pub format: Option<Format>,
+    /// File the report is written to
+    pub report: Option<PathBuf>,
+    /// Timeout of a single test in seconds, overrides the config
+    pub timeout: Option<f64>,
+    /// Timeout of all tests in seconds, overrides the config
+    pub global_timeout: Option<f64>,
+}
+
+impl TestOpts {
+    fn timeouts(&self, cfg: &Config) -> anyhow::Result<Timeouts> {
+        let mut timeouts = cfg.test_timeouts;
+        if let Some(secs) = self.timeout {
+            timeouts.test = harness::timeout(secs)?;
+        }
+        if let Some(secs) = self.global_timeout {
+            timeouts.global = harness::timeout(secs)?;
+        }
+        Ok(timeouts)
+    }
+
+    /// Format of the report and whether it's printed
+    /// instead of the pretty output
+    fn report_format(&self) -> anyhow::Result<(Format, bool)> {
+        match (&self.report, self.format) {
+            (Some(_), Some(Format::Pretty)) => {
+                bail!("The pretty format can't be written to a report, use junit or json")
+            }
+            (Some(_), Some(format)) => Ok((format, false)),
+            (Some(path), None) => Ok((Format::from_path(path), false)),
+            (None, Some(format)) => Ok((format, format != Format::Pretty)),
+            (None, None) => Ok((Format::Pretty, false)),
+        }
+    }
+}
+
+impl BuildOpts {
+    /// Options of a plain `surtur build`
+    fn dev() -> Self {
+        Self {
+            profile: DEV_PROFILE.into(),
+            target: None,
+            features: FeatureRequest::default(),
+            bin: None,
+            example: None,
+            examples: false,
+            jobs: jobs::default_jobs(),
+            locked: false,
+            rerun_scripts: false,
+        }
+    }
+
+    fn target(&self, cfg: &Config) -> Option<Target> {
+        self.target.as_ref().map(|triple| cfg.target(triple))
+    }
+
+    /// The executables that are built, all of them without `--bin`
+    fn bins(&self, cfg: &Config) -> anyhow::Result<Vec<Bin>> {
+        match &self.bin {
+            Some(name) => Ok(vec![find_bin(cfg, name)?]),
+            None => Ok(cfg.bins.clone()),
+        }
+    }
+
+    /// The examples that are built, the one selected
+    /// with `--example` or all of them with `--examples`
+    fn examples(&self, cfg: &Config) -> anyhow::Result<Vec<Bin>> {
+        if let Some(name) = &self.example {
+            return match cfg.examples.iter().find(|example| example.name == *name) {
+                Some(example) => Ok(vec![example.clone()]),
+                None => bail!(
+                    "Unknown example `{}`, available examples: {}",
+                    name,
+                    bin_names(&cfg.examples)
+                ),
+            };
+        }
+        match self.examples {
+            true => Ok(cfg.examples.clone()),
+            false => Ok(Vec::new()),
+        }
+    }
+
+    /// The executable that is run. Without `--bin` it's the entry
+    /// file of the project, or the only executable there is
+    fn run_bin(&self, cfg: &Config) -> anyhow::Result<Bin> {
+        if let Some(name) = &self.bin {
+            return find_bin(cfg, name);
+        }
+        match cfg.bins.as_slice() {
+            [] if !cfg.examples.is_empty() => bail!(
+                "There is no executable to run, run an example with `--example`: {}",
+                bin_names(&cfg.examples)
+            ),
+            // Libraries used to run `src/lib.c` as a demo
+            [] if cfg.entry.is_file() => bail!(
+                "There is no executable to run, libraries don't run {}. Move the demo into `examples/` and run it with `--example`",
+                cfg.entry.display()
+            ),
+            [] => bail!(
+                "There is no executable to run, add {} or a `Bins` table",
+                cfg.entry.display()
+            ),
+            [bin] => Ok(bin.clone()),
+            [bin, ..] if bin.entry == cfg.entry => Ok(bin.clone()),
+            bins => bail!(
+                "There are multiple executables, select one with `--bin`: {}",
+                bin_names(bins)
+            ),
+        }
+    }
+}
+
+fn find_bin(cfg: &Config, name: &str) -> anyhow::Result<Bin> {
+    match cfg.bins.iter().find(|bin| bin.name == name) {
+        Some(bin) => Ok(bin.clone()),
+        None => bail!(
+            "Unknown executable `{}`, available executables: {}",
+            name,
+            bin_names(&cfg.bins)
+        ),
+    }
+}
+
+fn bin_names(bins: &[Bin]) -> String {
+    bins.iter()
+        .map(|bin| format!("`{}`", bin.name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn run_c(cli: Cli, opts: &BuildOpts, args: Option<Vec<&String>>) -> anyhow::Result<()> {
     let cur_dir = cli.cur_dir.clone();
-    let root_name = util::root_dir_name(&cur_dir);
-    let executable_path = format!(
-        "./build/{}",
-        root_name.context("Failed to get root name of project")?
-    );
+    let cfg = cli.cfg.as_ref().context(MISSING_CFG)?;
+    let target = opts.target(cfg);
+    let build_dir = compiler::build_dir(&opts.profile, target.as_ref());
+    let executable_path = match &opts.example {
+        Some(example) => build_dir.join("examples").join(example),
+        None => build_dir.join(opts.run_bin(cfg)?.name),
+    };
+
+    // Checked before building, so a program that can't run isn't built
+    let mut cmd = program_command(target.as_ref(), &executable_path)?;
 
-    self::build_c(cli, enable_dbg, true, false)?;
+    self::build_c(cli, opts, true)?;
 
-    // Create a Command to run the executable
-    let mut cmd = Command::new(executable_path);
     if let Some(args) = args {
         cmd.args(args);
     }
@@ -35,90 +210,389 @@ pub fn run_c(cli: Cli, enable_dbg: bool, args: Option<Vec<&String>>) -> anyhow::
     util::run_c_program(&mut cmd, &cur_dir)
 }
 
-pub fn build_c(
-    cli: Cli,
-    enable_dbg: bool,
-    direct_execution: bool,
-    is_release: bool,
-) -> anyhow::Result<()> {
+pub fn build_c(cli: Cli, opts: &BuildOpts, direct_execution: bool) -> anyhow::Result<()> {
     let mut cfg = cli.cfg.context(MISSING_CFG)?;
-    if let Some(sm) = &cfg.scripts {
-        sm.pre_exec().context("Failed to run build scripts")?;
-    }
+    let target = opts.target(&cfg);
+    let out_path = compiler::build_dir(&opts.profile, target.as_ref());
+    let directives = pre_scripts(&cli.cur_dir, &cfg, opts, target.as_ref())?;
+    let (sources, include_dirs) = generated_sources(&cli.cur_dir, &cfg, &directives)?;
 
-    if direct_execution {
-        cfg.proj_type = ProjType::Bin;
-    } else {
+    // Examples use the project as a library. Every executable
+    // is built too, unless an example runs or `--bin` selects one
+    let examples = opts.examples(&cfg)?;
+    let bins = match direct_execution && !examples.is_empty() {
+        true => Vec::new(),
+        false => opts.bins(&cfg)?,
+    };
+    if bins.is_empty() || !examples.is_empty() {
         cfg.proj_type = ProjType::Lib;
+    } else {
+        cfg.proj_type = ProjType::Bin;
     }
 
-    let compiler = Compiler::new(&cli.cur_dir, &cfg)?;
-
-    let mut root_name = util::root_dir_name(&cli.cur_dir)
-        .context("Failed to get root name of project")?
-        .to_string();
+    let features = cfg
+        .features
+        .resolve(&opts.features)
+        .context("Invalid features")?;
+    let graph = resolve_deps(&cli.cur_dir, &cfg.active_deps(&features), opts.locked)?;
+    let compiler = Compiler::new(
+        &cli.cur_dir,
+        &cfg,
+        &opts.profile,
+        target.as_ref(),
+        graph,
+        opts.jobs,
+    )?;
 
-    let out_path = PathBuf::from("build");
+    let root_name =
+        util::root_dir_name(&cli.cur_dir).context("Failed to get root name of project")?;
 
-    if cfg.proj_type == ProjType::Lib {
-        root_name.push_str(".a");
-    }
-
-    if fs::metadata("./build").is_err() {
-        fs::create_dir("./build").context("Failed to create build directory")?
+    if !out_path.exists() {
+        fs::create_dir_all(&out_path).context("Failed to create build directory")?
     }
 
     compiler
         .build_deps()
         .context("Failed to build dependencies")?;
-        
+
+    let mut cflags = compiler.profile.cflags();
+    cflags.extend(features::define_flags(&cfg.enabled_defines(&features)));
+    cflags.extend(directives.cflags());
+    let mut ldflags = compiler.profile.ldflags.clone();
+    ldflags.extend(directives.ldflags());
     let ctx = CompileCtx {
         out_dir: &out_path,
         root_dir: &cli.cur_dir,
-        out_name: &root_name,
+        out_name: root_name,
         excluded: &cfg.excluded,
+        lib_kind: cfg.lib_kind,
+        version: &cfg.proj_version,
+        cflags: &cflags,
+        ldflags: &ldflags,
+        include_dirs: &include_dirs,
+        deps: compiler.dep_roots(),
+        bins: &bins,
+        sources: &sources,
     };
 
-    compiler.build(ctx, enable_dbg, is_release, false)?;
+    compiler.build(&ctx, false)?;
+    if cfg.proj_type == ProjType::Lib && !bins.is_empty() {
+        compiler.build_exe(&ctx, false)?;
+    }
+    if !examples.is_empty() {
+        compiler
+            .build_examples(&ctx, &examples)
+            .context("Failed to build examples")?;
+    }
+
+    if cfg.compdb {
+        write_compdb(&compiler, &cfg, &ctx)?;
+    }
 
     if let Some(sm) = &cfg.scripts {
-        sm.post_exec().context("Failed to run build scripts")?;
+        sm.post_exec(
+            &script_env(&cli.cur_dir, &cfg, opts, target.as_ref()),
+            opts.rerun_scripts,
+        )
+        .context("Failed to run build scripts")?;
     }
 
     Ok(())
 }
 
-pub fn run_test(cli: Cli, tests: &str) -> anyhow::Result<()> {
-    let mut cfg = cli.cfg.context(MISSING_CFG)?;
-    cfg.proj_type = ProjType::Bin;
-    let compiler = Compiler::new(&cli.cur_dir, &cfg)?;
+/// Information about the build passed to build scripts
+fn script_env<'a>(
+    root_dir: &'a Path,
+    cfg: &'a Config,
+    opts: &'a BuildOpts,
+    target: Option<&'a Target>,
+) -> ScriptEnv<'a> {
+    ScriptEnv {
+        root_dir,
+        name: &cfg.name,
+        version: &cfg.proj_version,
+        profile: &opts.profile,
+        target: target.map(|target| target.triple.as_str()),
+        out_dir: compiler::build_dir(&opts.profile, target),
+    }
+}
 
-    let build_dir = PathBuf::from("build");
+/// Run the generators and return all generated sources with
+/// the include directories of the project. The generated
+/// directory is on the include path if there are generators
+fn generated_sources(
+    root_dir: &Path,
+    cfg: &Config,
+    directives: &Directives,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut sources = directives.sources.clone();
+    sources.extend(
+        codegen::generate(root_dir, &cfg.generators).context("Failed to generate sources")?,
+    );
 
-    if !build_dir.exists() {
-        fs::create_dir(&build_dir).context("Failed to create build directory")?
+    let mut include_dirs = cfg.include_dirs.clone();
+    let gen_dir = root_dir.join(codegen::GEN_DIR);
+    if !cfg.generators.is_empty() || gen_dir.is_dir() {
+        include_dirs.push(gen_dir);
     }
+    Ok((sources, include_dirs))
+}
+
+/// Run the build scripts that run before the
+/// build and collect the directives they emit.
+/// Fails if the project isn't trusted to run
+/// its build scripts, generators and commands, see [`trust`]
+fn pre_scripts(
+    root_dir: &Path,
+    cfg: &Config,
+    opts: &BuildOpts,
+    target: Option<&Target>,
+) -> anyhow::Result<Directives> {
+    trust::ensure(root_dir, &cfg.unrestricted_code())?;
+    match &cfg.scripts {
+        Some(sm) => sm
+            .pre_exec(&script_env(root_dir, cfg, opts, target), opts.rerun_scripts)
+            .context("Failed to run build scripts"),
+        None => Ok(Directives::default()),
+    }
+}
+
+/// Build the tests of the project and run them, see [`harness`].
+/// With a machine readable format the report is written to
+/// the report file, or printed instead of the pretty output
+pub fn run_test(cli: Cli, test_opts: &TestOpts, opts: &BuildOpts) -> anyhow::Result<()> {
+    let (format, print_report) = test_opts.report_format()?;
+    let cfg = cli.cfg.context(MISSING_CFG)?;
+    let timeouts = test_opts.timeouts(&cfg)?;
+    let target = opts.target(&cfg);
+    let directives = pre_scripts(&cli.cur_dir, &cfg, opts, target.as_ref())?;
+    let (sources, include_dirs) = generated_sources(&cli.cur_dir, &cfg, &directives)?;
+    let tests_dir = cli
+        .cur_dir
+        .join(compiler::build_dir(&opts.profile, target.as_ref()))
+        .join("tests");
+    let test_bin = tests_dir.join(&cfg.name);
+    // Checked before building, so tests that can't run aren't built
+    program_command(target.as_ref(), &test_bin)?;
 
-    let tests_dir = build_dir.join("tests");
+    let features = cfg
+        .features
+        .resolve(&opts.features)
+        .context("Invalid features")?;
+    let graph = resolve_deps(&cli.cur_dir, &cfg.active_deps(&features), opts.locked)?;
+    let compiler = Compiler::new(
+        &cli.cur_dir,
+        &cfg,
+        &opts.profile,
+        target.as_ref(),
+        graph,
+        opts.jobs,
+    )?;
 
     if !tests_dir.exists() {
-        fs::create_dir(&tests_dir).context("Failed to create build/tests directory")?
+        fs::create_dir_all(&tests_dir).context("Failed to create tests directory")?
     }
 
     compiler.build_deps()?;
 
+    let mut cflags = compiler.profile.cflags();
+    cflags.extend(features::define_flags(&cfg.enabled_defines(&features)));
+    cflags.extend(directives.cflags());
+    let mut ldflags = compiler.profile.ldflags.clone();
+    ldflags.extend(directives.ldflags());
+    // The bundled `surtur.h` is found next to the public headers
+    let header_dir = tests_dir.join("include");
+    harness::write_header(&header_dir)?;
+    let mut include_dirs = include_dirs;
+    include_dirs.push(header_dir);
     let ctx = CompileCtx {
         excluded: &cfg.excluded,
         out_dir: &tests_dir,
         root_dir: &cli.cur_dir,
         out_name: &cfg.name,
+        lib_kind: cfg.lib_kind,
+        version: &cfg.proj_version,
+        cflags: &cflags,
+        ldflags: &ldflags,
+        include_dirs: &include_dirs,
+        deps: compiler.dep_roots(),
+        bins: &[],
+        sources: &sources,
+    };
+
+    let cases = harness::discover(&compiler.test_sources(&ctx))?;
+    let runner = harness::write_runner(&tests_dir, &cases)?;
+    compiler
+        .build_tests(&ctx, &runner)
+        .context("Failed to build tests")?;
+
+    let report = harness::run(
+        cases,
+        test_opts.filter.as_deref(),
+        timeouts,
+        print_report,
+        || {
+            let mut cmd = program_command(target.as_ref(), &test_bin)?;
+            cmd.env("SURTUR_PROJ_DIR", &cli.cur_dir);
+            Ok(cmd)
+        },
+    )?;
+
+    if let Some(content) = report::render(&report, format, &cfg.name, &cli.cur_dir) {
+        match &test_opts.report {
+            Some(path) => fs::write(path, content)
+                .context(format!("Failed to write test report: {}", path.display()))?,
+            None => print!("{}", content),
+        }
+    }
+    if !report.success() {
+        bail!(
+            "{} of {} tests failed",
+            report.results.len() - report.passed(),
+            report.results.len()
+        );
+    }
+    Ok(())
+}
+
+/// Command that runs a program built for `target`.
+/// Programs for other machines can only be run with
+/// the runner of their target, like `qemu-aarch64`
+fn program_command(target: Option<&Target>, program: &Path) -> anyhow::Result<Command> {
+    let Some(target) = target else {
+        return Ok(Command::new(program));
     };
+    match target.runner.as_deref() {
+        Some([runner, args @ ..]) => {
+            let mut cmd = Command::new(runner);
+            cmd.args(args).arg(program);
+            Ok(cmd)
+        }
+        _ if target.is_native() => Ok(Command::new(program)),
+        _ => bail!(
+            "Can't run programs built for `{}` on this machine, set a `runner` for the target in project.lua",
+            target.triple
+        ),
+    }
+}
 
-    compiler.build(ctx, false, false, true)?;
+/// Check out the dependency versions recorded in project.lock
+/// and resolve the dependency graph.
+/// A stale lockfile is only an error if `locked` is set
+fn resolve_deps(root_dir: &Path, dm: &DepManager, locked: bool) -> anyhow::Result<DepGraph> {
+    if dm.deps.is_empty() {
+        return Ok(DepGraph::default());
+    }
+    let lock = Lockfile::read(root_dir)?;
+    let graph = dm.resolve(false, |dep| {
+        match lock.as_ref().and_then(|lock| lock.entry(dep)) {
+            Some(entry) => global::checkout_commit(dep, &entry.commit),
+            None => Ok(()),
+        }
+    })?;
+    // Without a lockfile nothing is locked, which is only
+    // out of date if there is a git dependency to lock
+    if lock.unwrap_or_default().is_stale(&graph) {
+        if locked {
+            bail!(
+                "{} is out of date, run `surtur update` to update it",
+                LOCK_FILE
+            );
+        }
+        eprintln!(
+            "{} {} is out of date, run `surtur update` to update it",
+            "Warning:".yellow(),
+            LOCK_FILE
+        );
+    }
+    Ok(graph)
+}
 
-    env::set_var("SURTUR_TESTS", tests);
+/// Run the custom command `name` from the `Commands` table.
+/// Commands see the project like build scripts of the `dev`
+/// profile do and only run in trusted projects, see [`trust`]
+pub fn run_command(cli: Cli, name: &str, args: &[String]) -> anyhow::Result<()> {
+    let cfg = cli.cfg.context(MISSING_CFG)?;
+    let Some(command) = cfg.commands.iter().find(|command| command.name == name) else {
+        let names: Vec<&str> = cfg
+            .commands
+            .iter()
+            .map(|command| command.name.as_str())
+            .collect();
+        bail!(
+            "Unknown command `{}`, available commands: {}",
+            name,
+            match names.is_empty() {
+                true => "none".to_string(),
+                false => names.join(", "),
+            }
+        );
+    };
+    // Commands run processes and get the `surtur` table of build scripts
+    trust::ensure(&cli.cur_dir, &cfg.unrestricted_code())?;
+    let env = ScriptEnv {
+        root_dir: &cli.cur_dir,
+        name: &cfg.name,
+        version: &cfg.proj_version,
+        profile: DEV_PROFILE,
+        target: None,
+        out_dir: compiler::build_dir(DEV_PROFILE, None),
+    };
+    command.run(&cfg.lua, &env, args)
+}
 
-    let mut program = Command::new(tests_dir.join(cfg.name));
+/// Write the compilation database of the project
+/// with the flags that `surtur run` uses for the `dev` profile.
+/// Build scripts and generators run like they do before
+/// a build, so only if their inputs changed
+pub fn gen_compdb(cli: Cli) -> anyhow::Result<()> {
+    let mut cfg = cli.cfg.context(MISSING_CFG)?;
+    let opts = BuildOpts::dev();
+    let directives = pre_scripts(&cli.cur_dir, &cfg, &opts, None)?;
+    let (sources, include_dirs) = generated_sources(&cli.cur_dir, &cfg, &directives)?;
+    // Like `surtur build`, the project is built as its executables if it has any
+    cfg.proj_type = match cfg.bins.is_empty() {
+        true => ProjType::Lib,
+        false => ProjType::Bin,
+    };
+    let features = cfg.features.resolve(&opts.features)?;
+    let graph = cfg.active_deps(&features).resolve(false, |_| Ok(()))?;
+    let compiler = Compiler::new(&cli.cur_dir, &cfg, &opts.profile, None, graph, opts.jobs)?;
 
-    util::run_c_program(&mut program, &cli.cur_dir)
+    let root_name =
+        util::root_dir_name(&cli.cur_dir).context("Failed to get root name of project")?;
+
+    let mut cflags = compiler.profile.cflags();
+    cflags.extend(features::define_flags(&cfg.enabled_defines(&features)));
+    cflags.extend(directives.cflags());
+    let mut ldflags = compiler.profile.ldflags.clone();
+    ldflags.extend(directives.ldflags());
+    let ctx = CompileCtx {
+        out_dir: &compiler::build_dir(&opts.profile, None),
+        root_dir: &cli.cur_dir,
+        out_name: root_name,
+        excluded: &cfg.excluded,
+        lib_kind: cfg.lib_kind,
+        version: &cfg.proj_version,
+        cflags: &cflags,
+        ldflags: &ldflags,
+        include_dirs: &include_dirs,
+        deps: compiler.dep_roots(),
+        bins: &cfg.bins,
+        sources: &sources,
+    };
+
+    write_compdb(&compiler, &cfg, &ctx)
+}
+
+/// Write the compilation database with every executable of the
+/// project, no matter which ones the build selected. `surtur build`
+/// and `surtur compdb` write the same database this way
+fn write_compdb(compiler: &Compiler, cfg: &Config, ctx: &CompileCtx) -> anyhow::Result<()> {
+    let ctx = CompileCtx {
+        bins: &cfg.bins,
+        ..*ctx
+    };
+    let entries = compiler.compdb_entries(&ctx, false)?;
+    compdb::write(ctx.root_dir, &entries)
 }
@@ -0,0 +1,146 @@
+//! Features from the `Features` table of the config.
+//! A feature turns on defines, optional dependencies
+//! and other features. The features in `default`
+//! are enabled unless `--no-default-features` is passed
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use anyhow::{bail, Context};
+use mlua::{Table, Value};
+
+use super::config::string_list;
+
+pub const DEFAULT_FEATURE: &str = "default";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Feature {
+    /// Macros defined with `-D`, either `NAME` or `NAME=VALUE`
+    pub defines: Vec<String>,
+    /// Names of the optional dependencies the feature enables
+    pub deps: Vec<String>,
+    /// Other features the feature enables
+    pub features: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Features {
+    pub default: Vec<String>,
+    pub features: HashMap<String, Feature>,
+}
+
+/// Features requested for a project, either on the
+/// command line or by a project that depends on it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FeatureRequest {
+    pub features: Vec<String>,
+    pub default_features: bool,
+    pub all_features: bool,
+}
+
+impl Default for FeatureRequest {
+    fn default() -> Self {
+        Self {
+            features: Vec::new(),
+            default_features: true,
+            all_features: false,
+        }
+    }
+}
+
+impl FeatureRequest {
+    pub fn all() -> Self {
+        Self {
+            all_features: true,
+            ..Self::default()
+        }
+    }
+}
+
+impl Features {
+    /// The enabled features, including the ones
+    /// that are enabled by other features
+    pub fn resolve(&self, request: &FeatureRequest) -> anyhow::Result<BTreeSet<String>> {
+        if request.all_features {
+            return Ok(self.features.keys().cloned().collect());
+        }
+
+        let mut pending: Vec<&String> = request.features.iter().collect();
+        if request.default_features {
+            pending.extend(&self.default);
+        }
+        let mut enabled = BTreeSet::new();
+        while let Some(name) = pending.pop() {
+            let feature = self
+                .features
+                .get(name)
+                .context(format!("Unknown feature `{}`", name))?;
+            if enabled.insert(name.clone()) {
+                pending.extend(&feature.features);
+            }
+        }
+        Ok(enabled)
+    }
+
+    pub fn defines(&self, enabled: &BTreeSet<String>) -> Vec<String> {
+        enabled
+            .iter()
+            .filter_map(|name| self.features.get(name))
+            .flat_map(|feature| feature.defines.iter().cloned())
+            .collect()
+    }
+
+    /// Names of the optional dependencies enabled by the features
+    pub fn deps(&self, enabled: &BTreeSet<String>) -> HashSet<&str> {
+        enabled
+            .iter()
+            .filter_map(|name| self.features.get(name))
+            .flat_map(|feature| feature.deps.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// Parse the `Features` table
+    pub fn parse(table: Option<Table>) -> anyhow::Result<Self> {
+        let mut features = Self::default();
+        let Some(table) = table else {
+            return Ok(features);
+        };
+        for pair in table.pairs::<String, Value>() {
+            let (name, val) = pair.context("Invalid entry in `Features`")?;
+            if name == DEFAULT_FEATURE {
+                features.default = string_list("feature", &name, "default", val)?;
+                continue;
+            }
+            let Value::Table(table) = val else {
+                bail!("Feature `{}` has to be a table, found: {:?}", name, val);
+            };
+            let mut feature = Feature::default();
+            for pair in table.pairs::<String, Value>() {
+                let (key, val) = pair.context(format!("Invalid entry in feature `{}`", name))?;
+                match key.as_str() {
+                    "defines" => feature.defines = string_list("feature", &name, &key, val)?,
+                    "deps" => feature.deps = string_list("feature", &name, &key, val)?,
+                    "features" => feature.features = string_list("feature", &name, &key, val)?,
+                    key => bail!("Invalid key in feature `{}`: {}", name, key),
+                }
+            }
+            features.features.insert(name, feature);
+        }
+
+        for (name, feature) in &features.features {
+            for enabled in &feature.features {
+                if !features.features.contains_key(enabled) {
+                    bail!("Feature `{}` enables unknown feature `{}`", name, enabled);
+                }
+            }
+        }
+        Ok(features)
+    }
+}
+
+/// Compiler flags that define the macros
+pub fn define_flags(defines: &[String]) -> Vec<String> {
+    defines
+        .iter()
+        .map(|define| format!("-D{}", define))
+        .collect()
+}
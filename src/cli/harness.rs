@@ -0,0 +1,696 @@
+//! The test harness of `surtur test`.
+//! Tests are functions declared with `SURTUR_TEST(name)`
+//! from the bundled `surtur.h` header. They are discovered
+//! in the sources, linked into a generated runner and
+//! every test runs in its own process group, so a crashing
+//! or hanging test doesn't take the others down with it
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use colored::Colorize;
+use mlua::{Table, Value};
+
+/// Name of the bundled header that declares the test macros
+pub const HEADER_NAME: &str = "surtur.h";
+
+/// File name of the generated runner
+pub const RUNNER_NAME: &str = "surtur_tests.c";
+
+const HEADER: &str = r#"#pragma once
+
+#include <stdio.h>
+#include <stdlib.h>
+
+/* Declare a test, surtur discovers and runs it with `surtur test` */
+#define SURTUR_TEST(name) void surtur_test_##name(void)
+
+/* Fail the current test if `cond` is false */
+#define SURTUR_ASSERT(cond)                                                     \
+    do {                                                                        \
+        if (!(cond)) {                                                          \
+            fprintf(stderr, "%s:%d: assertion failed: %s\n", __FILE__, __LINE__, \
+                    #cond);                                                     \
+            exit(1);                                                            \
+        }                                                                       \
+    } while (0)
+
+#define SURTUR_ASSERT_EQ(a, b) SURTUR_ASSERT((a) == (b))
+"#;
+
+const TEST_MACRO: &str = "SURTUR_TEST(";
+
+/// Timeout of a single test if there is none in the config
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often a running test is checked for its timeout
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Timeouts from the `Tests` table of the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time a single test may take
+    pub test: Option<Duration>,
+    /// Time all tests together may take, the
+    /// tests that didn't run yet are skipped
+    pub global: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            test: Some(DEFAULT_TIMEOUT),
+            global: None,
+        }
+    }
+}
+
+impl Timeouts {
+    /// Parse the `Tests` table
+    pub fn parse(table: Option<Table>) -> anyhow::Result<Self> {
+        let mut timeouts = Self::default();
+        let Some(table) = table else {
+            return Ok(timeouts);
+        };
+        for pair in table.pairs::<String, Value>() {
+            let (key, val) = pair.context("Invalid entry in `Tests`")?;
+            let secs = match val {
+                Value::Integer(secs) => secs as f64,
+                Value::Number(secs) => secs,
+                val => bail!("`{}` of `Tests` has to be a number, found: {:?}", key, val),
+            };
+            match key.as_str() {
+                "timeout" => timeouts.test = timeout(secs)?,
+                "global_timeout" => timeouts.global = timeout(secs)?,
+                key => bail!("Invalid key in `Tests`: {}", key),
+            }
+        }
+        Ok(timeouts)
+    }
+}
+
+/// A timeout of `secs` seconds, `0` means no timeout
+pub fn timeout(secs: f64) -> anyhow::Result<Option<Duration>> {
+    if secs == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(secs)
+        .map(Some)
+        .context(format!("Invalid timeout: {}", secs))
+}
+
+/// A test function found in the sources
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+    pub name: String,
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// The test exited with a non-zero exit code
+    Failed(i32),
+    /// The test was killed by a signal, like `SIGSEGV`
+    Crashed(i32),
+    /// The test was killed because it ran out of time
+    TimedOut,
+    /// The test didn't run because the global timeout ran out
+    Skipped,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "{}", "ok".green()),
+            Outcome::Failed(code) => write!(f, "{} (exit code {})", "FAILED".red(), code),
+            Outcome::Crashed(signal) => {
+                write!(f, "{} ({})", "CRASHED".red(), signal_name(*signal))
+            }
+            Outcome::TimedOut => write!(f, "{}", "TIMED OUT".red()),
+            Outcome::Skipped => write!(f, "{}", "skipped".yellow()),
+        }
+    }
+}
+
+pub struct TestResult {
+    pub case: TestCase,
+    pub outcome: Outcome,
+    pub duration: Duration,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl TestResult {
+    /// Why the test didn't pass, the last line the
+    /// test wrote to stderr, like a failed assertion
+    pub fn message(&self) -> Option<String> {
+        let reason = match self.outcome {
+            Outcome::Passed => return None,
+            Outcome::Failed(code) => format!("exit code {}", code),
+            Outcome::Crashed(signal) => format!("killed by {}", signal_name(signal)),
+            Outcome::TimedOut => format!("timed out after {:.2}s", self.duration.as_secs_f64()),
+            Outcome::Skipped => return Some("not run, the global timeout ran out".into()),
+        };
+        match self
+            .stderr
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+        {
+            Some(line) => Some(format!("{} ({})", line.trim(), reason)),
+            None => Some(reason),
+        }
+    }
+}
+
+pub struct Report {
+    pub results: Vec<TestResult>,
+    /// Number of tests that didn't match the filter
+    pub filtered: usize,
+    pub duration: Duration,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.count(|outcome| outcome == Outcome::Passed)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, Outcome::Failed(_)))
+    }
+
+    pub fn crashed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, Outcome::Crashed(_)))
+    }
+
+    pub fn timed_out(&self) -> usize {
+        self.count(|outcome| outcome == Outcome::TimedOut)
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.count(|outcome| outcome == Outcome::Skipped)
+    }
+
+    pub fn success(&self) -> bool {
+        self.passed() == self.results.len()
+    }
+
+    fn count(&self, pred: impl Fn(Outcome) -> bool) -> usize {
+        self.results
+            .iter()
+            .filter(|result| pred(result.outcome))
+            .count()
+    }
+}
+
+/// Write `surtur.h` to `dir`. The file is only written
+/// if it changed, so the tests aren't recompiled every run
+pub fn write_header(dir: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(dir).context("Failed to create test include directory")?;
+    write_if_changed(&dir.join(HEADER_NAME), HEADER)
+}
+
+/// Find the tests declared in the source files,
+/// sorted by file and line
+pub fn discover(src_files: &[PathBuf]) -> anyhow::Result<Vec<TestCase>> {
+    let mut cases: Vec<TestCase> = Vec::new();
+    let mut seen: HashMap<String, PathBuf> = HashMap::new();
+    for file in src_files {
+        let content = fs::read_to_string(file)
+            .context(format!("Failed to read src file: {}", file.display()))?;
+        for line in content.lines() {
+            let Some(rest) = line.trim_start().strip_prefix(TEST_MACRO) else {
+                continue;
+            };
+            let Some((name, _)) = rest.split_once(')') else {
+                continue;
+            };
+            let name = name.trim();
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                bail!("Invalid test name in {}: `{}`", file.display(), name);
+            }
+            if let Some(other) = seen.insert(name.to_string(), file.clone()) {
+                bail!(
+                    "Test `{}` is declared twice, in {} and {}",
+                    name,
+                    other.display(),
+                    file.display()
+                );
+            }
+            cases.push(TestCase {
+                name: name.to_string(),
+                file: file.clone(),
+            });
+        }
+    }
+    Ok(cases)
+}
+
+/// Write the runner with the `main` function to `dir`.
+/// It runs the test passed as its first argument
+pub fn write_runner(dir: &Path, cases: &[TestCase]) -> anyhow::Result<PathBuf> {
+    let mut runner = String::from("#include <stdio.h>\n#include <string.h>\n\n");
+    for case in cases {
+        runner.push_str(&format!("void surtur_test_{}(void);\n", case.name));
+    }
+    runner.push_str(
+        "\nstatic const struct {\n    const char *name;\n    void (*run)(void);\n} TESTS[] = {\n",
+    );
+    for case in cases {
+        runner.push_str(&format!("    {{\"{0}\", surtur_test_{0}}},\n", case.name));
+    }
+    // An empty array isn't valid C
+    runner.push_str("    {NULL, NULL},\n};\n");
+    runner.push_str(concat!(
+        "\nint main(int argc, char **argv) {\n",
+        "    if (argc < 2) {\n",
+        "        fprintf(stderr, \"usage: %s <test>\\n\", argv[0]);\n",
+        "        return 2;\n",
+        "    }\n",
+        "    for (int i = 0; TESTS[i].name != NULL; i++) {\n",
+        "        if (strcmp(TESTS[i].name, argv[1]) == 0) {\n",
+        "            TESTS[i].run();\n",
+        "            return 0;\n",
+        "        }\n",
+        "    }\n",
+        "    fprintf(stderr, \"unknown test: %s\\n\", argv[1]);\n",
+        "    return 2;\n",
+        "}\n",
+    ));
+
+    let path = dir.join(RUNNER_NAME);
+    write_if_changed(&path, &runner)?;
+    Ok(path)
+}
+
+/// Run every test whose name contains `filter` in its own process.
+/// `command` creates the command that runs the test binary.
+/// Unless `quiet` is set, the progress and a summary are printed
+pub fn run(
+    cases: Vec<TestCase>,
+    filter: Option<&str>,
+    timeouts: Timeouts,
+    quiet: bool,
+    command: impl Fn() -> anyhow::Result<Command>,
+) -> anyhow::Result<Report> {
+    let total = cases.len();
+    let cases: Vec<TestCase> = cases
+        .into_iter()
+        .filter(|case| filter.is_none_or(|filter| case.name.contains(filter)))
+        .collect();
+
+    if !quiet {
+        println!(
+            "\nrunning {} test{}",
+            cases.len(),
+            if cases.len() == 1 { "" } else { "s" }
+        );
+    }
+    let start = Instant::now();
+    let global_deadline = timeouts.global.map(|timeout| start + timeout);
+    let mut results = Vec::new();
+    for case in cases {
+        let test_start = Instant::now();
+        if global_deadline.is_some_and(|deadline| test_start >= deadline) {
+            if !quiet {
+                println!("test {} ... {}", case.name, Outcome::Skipped);
+            }
+            results.push(TestResult {
+                case,
+                outcome: Outcome::Skipped,
+                duration: Duration::ZERO,
+                stdout: String::new(),
+                stderr: String::new(),
+            });
+            continue;
+        }
+        let deadline = match (
+            timeouts.test.map(|timeout| test_start + timeout),
+            global_deadline,
+        ) {
+            (Some(test), Some(global)) => Some(test.min(global)),
+            (test, global) => test.or(global),
+        };
+
+        let mut cmd = command()?;
+        cmd.arg(&case.name);
+        let (status, stdout, stderr) =
+            run_case(cmd, deadline).context(format!("Failed to run test `{}`", case.name))?;
+        let duration = test_start.elapsed();
+
+        let outcome = match status.map(|status| (status.code(), process::signal(&status))) {
+            None => Outcome::TimedOut,
+            Some((Some(0), _)) => Outcome::Passed,
+            Some((Some(code), _)) => Outcome::Failed(code),
+            Some((None, Some(signal))) => Outcome::Crashed(signal),
+            Some((None, None)) => Outcome::Failed(-1),
+        };
+        if !quiet {
+            println!(
+                "test {} ... {} ({:.2}s)",
+                case.name,
+                outcome,
+                duration.as_secs_f64()
+            );
+        }
+        results.push(TestResult {
+            case,
+            outcome,
+            duration,
+            stdout: String::from_utf8_lossy(&stdout).to_string(),
+            stderr: String::from_utf8_lossy(&stderr).to_string(),
+        });
+    }
+
+    let report = Report {
+        filtered: total - results.len(),
+        results,
+        duration: start.elapsed(),
+    };
+    if !quiet {
+        print_summary(&report);
+    }
+    Ok(report)
+}
+
+/// Run a single test in its own process group and collect its output.
+/// The whole group is killed once the test exits or the deadline
+/// is reached, so processes spawned by the test don't outlive it.
+/// There is no exit status if the test timed out
+fn run_case(
+    mut cmd: Command,
+    deadline: Option<Instant>,
+) -> anyhow::Result<(Option<ExitStatus>, Vec<u8>, Vec<u8>)> {
+    let mut child = process::spawn(
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+
+    // Read both pipes while waiting, a test that fills
+    // a pipe would block forever otherwise
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let exited = loop {
+        if process::has_exited(&mut child)? {
+            break true;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    process::kill(&mut child);
+    let status = child.wait()?;
+    let status = exited.then_some(status);
+
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    Ok((status, stdout, stderr))
+}
+
+fn read_pipe(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            // The output up to the error is still useful
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+pub use process::signal_name;
+
+/// Tests run in their own process group, the test is only reaped
+/// after its group is killed. Until then the pid stays reserved,
+/// so the group can't belong to another process
+#[cfg(unix)]
+mod process {
+    use std::{
+        io, mem,
+        os::unix::process::{CommandExt, ExitStatusExt},
+        process::{Child, Command, ExitStatus},
+    };
+
+    pub fn spawn(cmd: &mut Command) -> io::Result<Child> {
+        cmd.process_group(0).spawn()
+    }
+
+    /// Whether the process exited, without reaping it
+    pub fn has_exited(child: &mut Child) -> io::Result<bool> {
+        // SAFETY: an all zero `siginfo_t` is valid
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        // SAFETY: `info` is valid for writes
+        let res = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                child.id() as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `waitid` filled in `info`, the pid is 0 if the process is still running
+        Ok(unsafe { info.si_pid() } != 0)
+    }
+
+    /// Kill the process group of the test
+    pub fn kill(child: &mut Child) {
+        // SAFETY: `kill` has no memory safety requirements, the
+        // negative pid selects the process group of the test
+        unsafe {
+            libc::kill(-(child.id() as i32), libc::SIGKILL);
+        }
+    }
+
+    /// The signal that killed the process
+    pub fn signal(status: &ExitStatus) -> Option<i32> {
+        status.signal()
+    }
+
+    /// Name of a signal like `SIGSEGV`
+    pub fn signal_name(signal: i32) -> String {
+        let name = match signal {
+            libc::SIGSEGV => "SIGSEGV",
+            libc::SIGABRT => "SIGABRT",
+            libc::SIGFPE => "SIGFPE",
+            libc::SIGBUS => "SIGBUS",
+            libc::SIGILL => "SIGILL",
+            libc::SIGTRAP => "SIGTRAP",
+            libc::SIGKILL => "SIGKILL",
+            libc::SIGTERM => "SIGTERM",
+            libc::SIGINT => "SIGINT",
+            libc::SIGPIPE => "SIGPIPE",
+            libc::SIGSYS => "SIGSYS",
+            signal => return format!("signal {}", signal),
+        };
+        name.to_string()
+    }
+}
+
+/// Without process groups only the test itself is killed,
+/// and processes aren't killed by signals
+#[cfg(not(unix))]
+mod process {
+    use std::{
+        io,
+        process::{Child, Command, ExitStatus},
+    };
+
+    pub fn spawn(cmd: &mut Command) -> io::Result<Child> {
+        cmd.spawn()
+    }
+
+    pub fn has_exited(child: &mut Child) -> io::Result<bool> {
+        Ok(child.try_wait()?.is_some())
+    }
+
+    pub fn kill(child: &mut Child) {
+        // Fails if the test already exited
+        let _ = child.kill();
+    }
+
+    pub fn signal(_: &ExitStatus) -> Option<i32> {
+        None
+    }
+
+    pub fn signal_name(signal: i32) -> String {
+        format!("signal {}", signal)
+    }
+}
+
+/// Print the output of the failed tests and the summary line
+fn print_summary(report: &Report) {
+    let failures: Vec<&TestResult> = report
+        .results
+        .iter()
+        .filter(|result| !matches!(result.outcome, Outcome::Passed | Outcome::Skipped))
+        .collect();
+    if !failures.is_empty() {
+        println!("\nfailures:");
+        for failure in &failures {
+            println!(
+                "\n---- {} ({}) ----",
+                failure.case.name,
+                failure.case.file.display()
+            );
+            print!("{}{}", failure.stdout, failure.stderr);
+            // Failed assertions are already the last line of stderr
+            if failure.stderr.is_empty() {
+                if let Some(message) = failure.message() {
+                    println!("{}", message.red());
+                }
+            }
+        }
+    }
+
+    println!(
+        "\ntest result: {}. {} passed; {} failed; {} crashed; {} timed out; {} skipped; {} filtered out; finished in {:.2}s\n",
+        if report.success() {
+            "ok".green()
+        } else {
+            "FAILED".red()
+        },
+        report.passed(),
+        report.failed(),
+        report.crashed(),
+        report.timed_out(),
+        report.skipped(),
+        report.filtered,
+        report.duration.as_secs_f64()
+    );
+}
+
+fn write_if_changed(path: &Path, content: &str) -> anyhow::Result<()> {
+    if fs::read_to_string(path).is_ok_and(|old| old == content) {
+        return Ok(());
+    }
+    fs::write(path, content).context(format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// Write the sources to a new directory, named after the test
+    fn sources(test: &str, files: &[(&str, &str)]) -> (PathBuf, Vec<PathBuf>) {
+        let dir = env::temp_dir().join(format!("surtur-harness-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let paths = files
+            .iter()
+            .map(|(name, content)| {
+                let path = dir.join(name);
+                fs::write(&path, content).unwrap();
+                path
+            })
+            .collect();
+        (dir, paths)
+    }
+
+    fn case(name: &str) -> TestCase {
+        TestCase {
+            name: name.into(),
+            file: PathBuf::from("test.c"),
+        }
+    }
+
+    #[test]
+    fn discover_indented_tests() {
+        let (dir, files) = sources(
+            "indented",
+            &[
+                ("a.c", "SURTUR_TEST(first) {}\n#ifndef NOTESTS\n    SURTUR_TEST( second ) {\n}\n#endif\n"),
+                ("b.c", "\tSURTUR_TEST(third) {}\n// not a test: SURTUR_TEST(fourth)\n"),
+            ],
+        );
+        let cases = discover(&files).unwrap();
+        let names: Vec<&str> = cases.iter().map(|case| case.name.as_str()).collect();
+        assert_eq!(names, ["first", "second", "third"]);
+        assert_eq!(cases[2].file, files[1]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn discover_rejects_duplicates() {
+        let (dir, files) = sources(
+            "duplicate",
+            &[
+                ("a.c", "SURTUR_TEST(same) {}\n"),
+                ("b.c", "SURTUR_TEST(same) {}\n"),
+            ],
+        );
+        let err = discover(&files).unwrap_err().to_string();
+        assert!(err.contains("`same` is declared twice"), "{}", err);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn discover_rejects_invalid_names() {
+        for src in ["SURTUR_TEST(bad-name) {}\n", "SURTUR_TEST() {}\n"] {
+            let (dir, files) = sources("invalid", &[("a.c", src)]);
+            let err = discover(&files).unwrap_err().to_string();
+            assert!(err.starts_with("Invalid test name"), "{}", err);
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn runner_without_tests() {
+        let (dir, _) = sources("runner", &[]);
+        let runner = fs::read_to_string(write_runner(&dir, &[]).unwrap()).unwrap();
+        assert!(!runner.contains("surtur_test_"));
+        assert!(runner.contains("TESTS[] = {\n    {NULL, NULL},\n};"));
+
+        let runner = fs::read_to_string(write_runner(&dir, &[case("one")]).unwrap()).unwrap();
+        assert!(runner.contains("void surtur_test_one(void);"));
+        assert!(runner.contains("    {\"one\", surtur_test_one},\n    {NULL, NULL},\n"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn filtered_tests_are_counted() {
+        let cases = vec![case("parse_a"), case("parse_b"), case("emit")];
+        let report = run(cases, Some("nothing"), Timeouts::default(), true, || {
+            bail!("no test matches the filter")
+        })
+        .unwrap();
+        assert!(report.results.is_empty());
+        assert_eq!(report.filtered, 3);
+        assert!(report.success());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filtered_tests_are_counted_next_to_results() {
+        let cases = vec![case("parse_a"), case("parse_fails"), case("emit")];
+        // The name of the test is passed as `$0`
+        let report = run(cases, Some("parse"), Timeouts::default(), true, || {
+            let mut cmd = Command::new("sh");
+            cmd.args(["-c", "test \"$0\" != parse_fails"]);
+            Ok(cmd)
+        })
+        .unwrap();
+        assert_eq!(report.filtered, 1);
+        assert_eq!(report.results.len(), 2);
+        assert_eq!(report.passed(), 1);
+        assert_eq!(report.failed(), 1);
+        assert!(!report.success());
+    }
+}
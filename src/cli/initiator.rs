@@ -5,7 +5,6 @@
 /// 
 /// Later on, it will also be able
 /// to migrate Make projects
-
 use std::fs;
 
 use super::creator::Project;
@@ -18,7 +17,7 @@ pub fn init_proj(proj: &Project) -> anyhow::Result<()> {
     }
 
     if fs::metadata(main_file).is_err() {
-        Project::create_main_file(proj.root_dir, false)?;
+        Project::create_main_file(proj.root_dir)?;
     }
 
     Ok(())
@@ -0,0 +1,131 @@
+//! Handling of the project's lockfile (project.lock).
+//! It records the exact commit every dependency
+//! was resolved to by `surtur update`, so every
+//! build uses the same code.
+//!
+//! The lockfile is a lua file like the config,
+//! but it is generated and should not be edited
+
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use mlua::Table;
+
+use crate::util;
+
+use super::{
+    deps::{DepGraph, Dependency},
+    sandbox,
+};
+
+pub const LOCK_FILE: &str = "project.lock";
+
+#[derive(Debug, Default)]
+pub struct Lockfile {
+    pub entries: Vec<LockEntry>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LockEntry {
+    pub name: String,
+    pub origin: String,
+    /// The version requirement the entry was resolved from
+    pub req: Option<String>,
+    /// The branch, tag or revision the entry was resolved from
+    pub git_ref: Option<String>,
+    /// The tag the requirement resolved to
+    pub tag: Option<String>,
+    pub commit: String,
+}
+
+impl LockEntry {
+    /// Whether this entry was resolved from the dependency as it
+    /// is currently declared in project.lua
+    pub fn matches(&self, dep: &Dependency) -> bool {
+        dep.origin() == Some(&self.origin)
+            && self.req == dep.version.as_ref().map(|req| req.to_string())
+            && self.git_ref == dep.git_ref.as_ref().map(|git_ref| git_ref.to_string())
+    }
+}
+
+impl Lockfile {
+    /// Read the lockfile of the project if there is one
+    pub fn read(root_dir: &Path) -> anyhow::Result<Option<Self>> {
+        let path = root_dir.join(LOCK_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path).context("Failed to read project.lock")?;
+
+        let lua = sandbox::new()?;
+        sandbox::eval(&lua, LOCK_FILE, &content)?;
+
+        let mut entries = Vec::new();
+        let lock_table: Table = lua
+            .globals()
+            .get("Lock")
+            .context("Failed to get `Lock` table from project.lock")?;
+        for entry in lock_table.sequence_values::<Table>() {
+            let entry = entry.context("Invalid entry in project.lock")?;
+            entries.push(LockEntry {
+                name: entry.get("name")?,
+                origin: entry.get("origin")?,
+                req: entry.get("req")?,
+                git_ref: entry.get("ref")?,
+                tag: entry.get("tag")?,
+                commit: entry.get("commit")?,
+            });
+        }
+        Ok(Some(Self { entries }))
+    }
+
+    pub fn write(&self, root_dir: &Path) -> anyhow::Result<()> {
+        let mut content = String::from(concat!(
+            "-- This file is generated by surtur, do not edit it manually.\n",
+            "-- Run `surtur update` to update it.\n",
+            "Lock = {\n"
+        ));
+        for entry in &self.entries {
+            content.push_str("    {\n");
+            content.push_str(&format!("        name = {},\n", util::lua_str(&entry.name)));
+            content.push_str(&format!(
+                "        origin = {},\n",
+                util::lua_str(&entry.origin)
+            ));
+            if let Some(req) = &entry.req {
+                content.push_str(&format!("        req = {},\n", util::lua_str(req)));
+            }
+            if let Some(git_ref) = &entry.git_ref {
+                content.push_str(&format!("        ref = {},\n", util::lua_str(git_ref)));
+            }
+            if let Some(tag) = &entry.tag {
+                content.push_str(&format!("        tag = {},\n", util::lua_str(tag)));
+            }
+            content.push_str(&format!(
+                "        commit = {},\n",
+                util::lua_str(&entry.commit)
+            ));
+            content.push_str("    },\n");
+        }
+        content.push_str("}\n");
+        fs::write(root_dir.join(LOCK_FILE), content).context("Failed to write project.lock")
+    }
+
+    #[inline]
+    pub fn entry(&self, dep: &Dependency) -> Option<&LockEntry> {
+        self.entries.iter().find(|entry| entry.matches(dep))
+    }
+
+    /// The lockfile is stale if a git dependency of the
+    /// dependency graph is missing from it.
+    /// It also locks optional dependencies, so it can contain
+    /// dependencies that aren't enabled. Local dependencies are not locked
+    pub fn is_stale(&self, graph: &DepGraph) -> bool {
+        graph
+            .nodes
+            .iter()
+            .map(|node| &node.dep)
+            .filter(|dep| dep.origin().is_some())
+            .any(|dep| self.entry(dep).is_none())
+    }
+}
@@ -1,25 +1,46 @@
 /// Handling of commands, arguments.
 /// Also interacts with config module to
 /// gather/store configuration.
+pub mod codegen;
+pub mod commands;
+pub mod compdb;
 pub mod compiler;
 pub mod config;
 pub mod creator;
 pub mod deps;
+pub mod editor;
 pub mod executor;
+pub mod features;
+pub mod harness;
 pub mod initiator;
+pub mod lock;
+pub mod profile;
+pub mod report;
+pub mod sandbox;
 pub mod scripts;
+pub mod target;
+pub mod trust;
 
-use std::{env, path::PathBuf};
+use std::{collections::HashSet, env, fs, path::PathBuf};
 
 use anyhow::{bail, Context};
-use clap::{arg, command, value_parser, ArgMatches, Command as CCommand};
+use clap::{arg, command, value_parser, Arg, ArgMatches, Command as CCommand};
 
 use crate::{
     subcommand,
-    util::{files::FileHandler, MISSING_CFG},
+    util::{files::FileHandler, jobs, MISSING_CFG},
 };
 
-use self::{config::Config, creator::Project};
+use self::{
+    commands::CustomCommand,
+    config::Config,
+    creator::Project,
+    deps::{DepManager, Dependency, DEFAULT_ORIGIN},
+    executor::{BuildOpts, TestOpts},
+    features::FeatureRequest,
+    profile::{DEV_PROFILE, RELEASE_PROFILE},
+    report::Format,
+};
 
 const INTRO: &str = r#"
 This is the Surtur build tool for C
@@ -32,8 +53,46 @@ The most important commands are:
 - remove <name> // removes the specified library
 - update // Update & install dependencies
 - init // initialize a surtur C project
+- x <name> // run a command from the Commands table
+- trust // allow the project to run its build scripts and commands
 "#;
 
+/// Args shared by all commands that build the project
+fn build_args() -> [Arg; 8] {
+    [
+        arg!(-p --profile <NAME> "Build profile from the Profiles table (defaults to `dev`)")
+            .required(false),
+        arg!(--target <TRIPLE> "Cross compile for the target, e.g. `aarch64-linux-gnu`")
+            .required(false),
+        arg!(-F --features <FEATURES> "Comma separated list of features to enable")
+            .required(false)
+            .value_delimiter(','),
+        arg!(--"all-features" "Enable all features").required(false),
+        arg!(--"no-default-features" "Don't enable the `default` feature").required(false),
+        arg!(-j --jobs <N> "Number of parallel compile jobs (defaults to the number of CPUs)")
+            .required(false)
+            .value_parser(value_parser!(usize)),
+        arg!(--locked "Fail if project.lock is out of date").required(false),
+        arg!(--"rerun-scripts" "Run build scripts even if their inputs didn't change")
+            .required(false),
+    ]
+}
+
+/// Arguments that are passed on to a custom command as they are
+fn command_args_arg() -> Arg {
+    arg!(<ARGS> ... "Arguments of the command")
+        .required(false)
+        .trailing_var_arg(true)
+        .allow_hyphen_values(true)
+}
+
+fn command_args(matches: &ArgMatches) -> Vec<String> {
+    matches
+        .get_many::<String>("ARGS")
+        .map(|args| args.cloned().collect())
+        .unwrap_or_default()
+}
+
 pub struct Cli {
     pub cfg: Option<Config>,
     pub cur_dir: PathBuf,
@@ -63,26 +122,32 @@ impl Cli {
     }
 
     fn match_args(self) -> anyhow::Result<()> {
-        match Self::handle_cmd() {
+        let commands = match &self.cfg {
+            Some(cfg) => cfg.commands.as_slice(),
+            None => &[],
+        };
+        match Self::handle_cmd(commands) {
             m if m.subcommand_matches("run").is_some() => {
                 let matches = m.subcommand_matches("run").unwrap();
 
                 let args: Option<Vec<&String>> =
                     matches.get_many("PROGRAM_ARGS").map(|many| many.collect());
 
-                let enable_dbg = matches.get_flag("debug");
-
-                executor::run_c(self, enable_dbg, args)?
+                executor::run_c(self, &Self::build_opts(matches), args)?
             }
             m if m.subcommand_matches("build").is_some() => {
                 let matches = m.subcommand_matches("build").unwrap();
 
-                let enable_dbg = matches.get_flag("debug");
-                let is_release = matches.get_flag("release");
-
-                executor::build_c(self, enable_dbg, false, is_release)
+                executor::build_c(self, &Self::build_opts(matches), false)
                     .context("Failed to build program as executable")?;
             }
+            m if m.subcommand_matches("add").is_some() => {
+                self.add_dep(m.subcommand_matches("add").unwrap())?
+            }
+            m if m.subcommand_matches("remove").is_some() => {
+                self.remove_dep(m.subcommand_matches("remove").unwrap())?
+            }
+            m if m.subcommand_matches("compdb").is_some() => executor::gen_compdb(self)?,
             m if m.subcommand_matches("init").is_some() => {
                 initiator::init_proj(&Project::new(&self.cur_dir))?;
             }
@@ -92,24 +157,60 @@ impl Cli {
             }
             // Switch this to if let guards once they are stabelized
             m if m.subcommand_matches("new").is_some() => Self::new_proj(m)?,
+            m if m.subcommand_matches("trust").is_some() => {
+                self.trust(m.subcommand_matches("trust").unwrap().get_flag("revoke"))?
+            }
+            m if m.subcommand_matches("x").is_some() => {
+                let matches = m.subcommand_matches("x").unwrap();
+                let name = matches
+                    .get_one::<String>("NAME")
+                    .context("Failed to get the NAME argument")?
+                    .clone();
+                executor::run_command(self, &name, &command_args(matches))?
+            }
+            // Custom commands that don't collide with a built-in command
+            m if m.subcommand_name().is_some() => {
+                let (name, matches) = m.subcommand().unwrap();
+                let args = command_args(matches);
+                executor::run_command(self, name, &args)?
+            }
             _ => println!("{}", INTRO),
         }
         Ok(())
     }
 
-    fn handle_cmd() -> ArgMatches {
-        command!()
+    fn handle_cmd(commands: &[CustomCommand]) -> ArgMatches {
+        let cmd = command!()
             .subcommand(
                 CCommand::new("run")
                 .about("Run the current binary project")
                 .arg(
-                    arg!(-d --debug "Enable debug mode for this project")
+                    arg!(-r --release "Shorthand for `--profile release`")
+                        .required(false)
+                        .conflicts_with("profile")
+                )
+                .arg(
+                    arg!(-d --debug "Shorthand for `--profile dev`")
+                        .required(false)
+                        .conflicts_with("profile")
+                )
+                .arg(
+                    arg!(--bin <NAME> "Executable to run")
                         .required(false)
                 )
+                .arg(
+                    arg!(--example <NAME> "Example from `examples/` to run")
+                        .required(false)
+                        .conflicts_with("bin")
+                )
+                .args(build_args())
                 .arg(
                     arg!(<PROGRAM_ARGS> ... "Args")
                         .required(false)
                 )
+            ).subcommand(
+                CCommand::new("compdb")
+                .about("Generate a compile_commands.json for editors and clangd")
             ).subcommand(
                 CCommand::new("init")
                 .about("Initialize a surtur project in the current directory")
@@ -117,27 +218,63 @@ impl Cli {
                 CCommand::new("build")
                     .about("Build the project into a library or executable")
                     .arg(
-                        arg!(-r --release "Compile the program in release mode (better optimization)")
-                            .required(false),
+                        arg!(-r --release "Shorthand for `--profile release`")
+                            .required(false)
+                            .conflicts_with("profile"),
+                    )
+                    .arg(
+                        arg!(-d --debug "Shorthand for `--profile dev`")
+                            .required(false)
+                            .conflicts_with("profile"),
                     )
                     .arg(
-                        arg!(-d --debug "Compile the program in debug mode (more advanced debugging capabilities)")
+                        arg!(--bin <NAME> "Only build this executable")
                             .required(false),
-                    ),
+                    )
+                    .arg(
+                        arg!(--examples "Also build all examples from `examples/`")
+                            .required(false)
+                            .conflicts_with("bin"),
+                    )
+                    .args(build_args()),
             ).subcommand(
-                subcommand!("test", "Run a specific or all tests",
-                arg!(<NAME> "Specify a test name").required(false))
+                CCommand::new("test")
+                    .about("Run all tests or the ones whose name contains the filter")
+                    .arg(arg!(<FILTER> "Only run tests whose name contains the filter").required(false))
+                    .arg(
+                        arg!(--format <FORMAT> "Output format, or format of the report file")
+                            .required(false)
+                            .value_parser(Format::NAMES),
+                    )
+                    .arg(
+                        arg!(--report <PATH> "Write a report to the file, JUnit XML unless it ends with .json")
+                            .required(false)
+                            .value_parser(value_parser!(PathBuf)),
+                    )
+                    .arg(
+                        arg!(--timeout <SECS> "Time a single test may take, 0 for no limit")
+                            .required(false)
+                            .value_parser(value_parser!(f64)),
+                    )
+                    .arg(
+                        arg!(--"global-timeout" <SECS> "Time all tests together may take, 0 for no limit")
+                            .required(false)
+                            .value_parser(value_parser!(f64)),
+                    )
+                    .args(build_args())
+            )
+            .subcommand(CCommand::new("add")
+                .about("Add a dependency to the project")
+                .arg(arg!(<DEPENDENCY> "Git url or name of the dependency"))
+                .arg(
+                    arg!(--version <VERSION> "Version requirement of the dependency, e.g. \"^0.1\"")
+                        .required(false)
+                )
+            )
+            .subcommand(CCommand::new("remove")
+                .about("Remove a dependency from the project")
+                .arg(arg!(<DEPENDENCY> "Name of the dependency to remove"))
             )
-            .subcommand(subcommand!(
-                "add",
-                "Create a new project",
-                arg!(<NAME> "Name for the project")
-            ))
-            .subcommand(subcommand!(
-                "remove",
-                "remove a dependency",
-                arg!(<DEPENDENCY> "dependency to remove")
-            ))
             .subcommand(subcommand!(
                 "new",
                 "create a new project",
@@ -148,26 +285,193 @@ impl Cli {
                 .arg(
                     arg!(-f --force "Force update dependencies, even if there is no new version")
                         .required(false))
-            ).get_matches()
+            )
+            .subcommand(CCommand::new("trust")
+                .about("Allow the project to run its build scripts, generators and commands")
+                .arg(
+                    arg!(--revoke "Don't trust the project anymore")
+                        .required(false))
+            )
+            .subcommand(CCommand::new("x")
+                .about("Run a command from the Commands table of project.lua")
+                .arg(arg!(<NAME> "Name of the command"))
+                .arg(command_args_arg())
+            );
+        // Built-in commands win over custom ones with the same name,
+        // those can still be run with `surtur x <name>`. Clap only
+        // adds its `help` subcommand when matching, so it's not found
+        let custom: Vec<CCommand> = commands
+            .iter()
+            .filter(|command| {
+                command.name != "help" && cmd.find_subcommand(&command.name).is_none()
+            })
+            .map(|command| {
+                CCommand::new(command.name.clone())
+                    .about(
+                        command
+                            .description
+                            .clone()
+                            .unwrap_or_else(|| "Custom command from project.lua".into()),
+                    )
+                    .arg(command_args_arg())
+            })
+            .collect();
+        cmd.subcommands(custom).get_matches()
     }
 
     fn run_test(self, m: ArgMatches) -> anyhow::Result<()> {
         let cmd = m
             .subcommand_matches("test")
             .context("Failed to match subcommand `test`")?;
-        let tests = cmd.get_one::<PathBuf>("NAME");
-        executor::run_test(
-            self,
-            &match tests {
-                Some(tests) => tests.to_string_lossy().to_string(),
-                None => "*".into(),
+        let test_opts = TestOpts {
+            filter: cmd.get_one::<String>("FILTER").cloned(),
+            format: cmd
+                .get_one::<String>("format")
+                .map(|format| format.parse())
+                .transpose()?,
+            report: cmd.get_one::<PathBuf>("report").cloned(),
+            timeout: cmd.get_one::<f64>("timeout").copied(),
+            global_timeout: cmd.get_one::<f64>("global-timeout").copied(),
+        };
+        let opts = Self::build_opts(cmd);
+        executor::run_test(self, &test_opts, &opts)
+    }
+
+    /// Collect the options of commands that build the project.
+    /// Not every command has every flag, so missing ones are `false`
+    fn build_opts(matches: &ArgMatches) -> BuildOpts {
+        let flag = |name| {
+            matches
+                .try_get_one::<bool>(name)
+                .ok()
+                .flatten()
+                .copied()
+                .unwrap_or(false)
+        };
+        let profile = match matches.get_one::<String>("profile") {
+            Some(profile) => profile.clone(),
+            None if flag("release") => RELEASE_PROFILE.into(),
+            None => DEV_PROFILE.into(),
+        };
+        BuildOpts {
+            profile,
+            target: matches.get_one::<String>("target").cloned(),
+            bin: matches.try_get_one::<String>("bin").ok().flatten().cloned(),
+            example: matches
+                .try_get_one::<String>("example")
+                .ok()
+                .flatten()
+                .cloned(),
+            examples: flag("examples"),
+            features: FeatureRequest {
+                features: matches
+                    .get_many::<String>("features")
+                    .map(|features| features.cloned().collect())
+                    .unwrap_or_default(),
+                default_features: !flag("no-default-features"),
+                all_features: flag("all-features"),
             },
-        )
+            // Defaults to the number of CPUs
+            jobs: matches
+                .get_one::<usize>("jobs")
+                .copied()
+                .unwrap_or_else(jobs::default_jobs),
+            locked: flag("locked"),
+            rerun_scripts: flag("rerun-scripts"),
+        }
     }
 
+    /// Download the dependencies and write the resolved commits to
+    /// project.lock. This is the only command that changes the lockfile
     fn update(&self, forced: bool) -> anyhow::Result<()> {
         let dep_manager = &self.cfg.as_ref().context(MISSING_CFG)?.deps;
-        dep_manager.download_deps(forced)
+        dep_manager.download_deps(forced)?.write(&self.cur_dir)
+    }
+
+    /// Add the project to the trusted projects or remove it
+    fn trust(&self, revoke: bool) -> anyhow::Result<()> {
+        self.cfg.as_ref().context(MISSING_CFG)?;
+        match revoke {
+            true if trust::revoke(&self.cur_dir)? => println!("The project is not trusted anymore"),
+            true => println!("The project was not trusted"),
+            false if trust::trust(&self.cur_dir)? => {
+                println!("The project may run its build scripts, generators and commands now")
+            }
+            false => println!("The project is already trusted"),
+        }
+        Ok(())
+    }
+
+    /// Add a dependency to project.lua and download it.
+    /// A plain name refers to a project of the Surtur-Team
+    fn add_dep(&self, cmd: &ArgMatches) -> anyhow::Result<()> {
+        let cfg = self.cfg.as_ref().context(MISSING_CFG)?;
+        let name = cmd
+            .get_one::<String>("DEPENDENCY")
+            .context("Failed to get the DEPENDENCY argument")?;
+        let version = cmd.get_one::<String>("version").map(String::as_str);
+
+        let origin = if name.contains('/') || name.contains(':') {
+            name.clone()
+        } else {
+            format!("{}/{}", DEFAULT_ORIGIN, name)
+        };
+        let dep = Dependency::new(&origin, version)?;
+        let dep_name = dep.name()?;
+
+        for existing in &cfg.deps.deps {
+            if existing.name()? == dep_name {
+                bail!("Dependency `{}` is already in project.lua", dep_name);
+            }
+        }
+
+        // Download first, so project.lua only changes if the dependency exists
+        let cfg_path = self.cur_dir.join("project.lua");
+        let src = fs::read_to_string(&cfg_path).context(MISSING_CFG)?;
+        DepManager::new(HashSet::from([dep])).download_deps(false)?;
+        fs::write(&cfg_path, editor::add_dep(&src, &origin, version))
+            .context("Failed to write project.lua")?;
+        println!(
+            "Added {}, run `surtur update` to update project.lock",
+            dep_name
+        );
+        Ok(())
+    }
+
+    /// Remove a dependency from project.lua and
+    /// delete its build artifacts of every profile and target
+    fn remove_dep(&self, cmd: &ArgMatches) -> anyhow::Result<()> {
+        self.cfg.as_ref().context(MISSING_CFG)?;
+        let name = cmd
+            .get_one::<String>("DEPENDENCY")
+            .context("Failed to get the DEPENDENCY argument")?;
+
+        let cfg_path = self.cur_dir.join("project.lua");
+        let src = fs::read_to_string(&cfg_path).context(MISSING_CFG)?;
+        fs::write(&cfg_path, editor::remove_dep(&src, name)?)
+            .context("Failed to write project.lua")?;
+
+        // Builds are in `build/<profile>/` or `build/<triple>/<profile>/`,
+        // the dependency in their `deps/<name>/`
+        let dep_name = deps::dep_name(name);
+        let mut dirs = vec![self.cur_dir.join("build")];
+        for _ in 0..2 {
+            let parents = std::mem::take(&mut dirs);
+            for entry in parents.iter().flat_map(fs::read_dir).flatten().flatten() {
+                let build_dir = entry.path();
+                if !build_dir.is_dir() || dep_name.is_empty() {
+                    continue;
+                }
+                let dep_dir = build_dir.join(compiler::DEPS_DIR).join(dep_name);
+                if dep_dir.is_dir() {
+                    fs::remove_dir_all(&dep_dir)
+                        .context(format!("Failed to remove {}", dep_dir.display()))?;
+                }
+                dirs.push(build_dir);
+            }
+        }
+        println!("Removed {}", name);
+        Ok(())
     }
 
     fn new_proj(m: ArgMatches) -> anyhow::Result<()> {
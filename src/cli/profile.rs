@@ -0,0 +1,182 @@
+//! Build profiles from the `Profiles` table of the config.
+//! Every profile describes how the project is compiled
+//! and gets its own output directory (`build/<profile>/`).
+//! The `dev` and `release` profiles always exist and can
+//! be changed, custom profiles inherit from `dev` unless
+//! they specify another profile with `inherits`
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use mlua::{Table, Value};
+
+use super::config::string_list;
+
+pub const DEV_PROFILE: &str = "dev";
+pub const RELEASE_PROFILE: &str = "release";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    /// Optimization level passed as `-O<opt_level>`
+    pub opt_level: String,
+    /// Include debug information (`-g`)
+    pub debug: bool,
+    /// Macros defined with `-D`, either `NAME` or `NAME=VALUE`
+    pub defines: Vec<String>,
+    /// Warnings enabled with `-W`, like `all` or `extra`
+    pub warnings: Vec<String>,
+    pub cflags: Vec<String>,
+    pub ldflags: Vec<String>,
+}
+
+impl Profile {
+    pub fn dev() -> Self {
+        Self {
+            opt_level: "0".into(),
+            debug: true,
+            defines: Vec::new(),
+            warnings: Vec::new(),
+            cflags: Vec::new(),
+            ldflags: Vec::new(),
+        }
+    }
+
+    pub fn release() -> Self {
+        Self {
+            opt_level: "3".into(),
+            debug: false,
+            ..Self::dev()
+        }
+    }
+
+    /// Flags used to compile the project's own sources
+    pub fn cflags(&self) -> Vec<String> {
+        let mut flags = self.codegen_flags();
+        flags.extend(self.defines.iter().map(|define| format!("-D{}", define)));
+        flags.extend(self.warnings.iter().map(|warning| format!("-W{}", warning)));
+        flags.extend(self.cflags.iter().cloned());
+        flags
+    }
+
+    /// Flags that also apply to the sources of dependencies
+    pub fn codegen_flags(&self) -> Vec<String> {
+        let mut flags = vec![format!("-O{}", self.opt_level)];
+        if self.debug {
+            flags.push("-g".into());
+        }
+        flags
+    }
+
+    /// Overwrite the fields that are set in the table
+    fn apply(&mut self, name: &str, table: &Table) -> anyhow::Result<()> {
+        for pair in table.clone().pairs::<String, Value>() {
+            let (key, val) = pair.context(format!("Invalid entry in profile `{}`", name))?;
+            match key.as_str() {
+                "opt" => {
+                    self.opt_level = match val {
+                        Value::Integer(level) => level.to_string(),
+                        Value::String(level) => level.to_str()?.to_string(),
+                        val => bail!(
+                            "`opt` of profile `{}` has to be a number or string, found: {:?}",
+                            name,
+                            val
+                        ),
+                    }
+                }
+                "debug" => {
+                    self.debug = match val {
+                        Value::Boolean(debug) => debug,
+                        val => bail!(
+                            "`debug` of profile `{}` has to be a boolean, found: {:?}",
+                            name,
+                            val
+                        ),
+                    }
+                }
+                "defines" => self.defines = string_list("profile", name, &key, val)?,
+                "warnings" => self.warnings = string_list("profile", name, &key, val)?,
+                "cflags" => self.cflags = string_list("profile", name, &key, val)?,
+                "ldflags" => self.ldflags = string_list("profile", name, &key, val)?,
+                "inherits" => (),
+                key => bail!("Invalid key in profile `{}`: {}", name, key),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse the `Profiles` table, including the builtin profiles
+pub fn parse_profiles(table: Option<Table>) -> anyhow::Result<HashMap<String, Profile>> {
+    let mut tables = HashMap::new();
+    if let Some(table) = table {
+        for pair in table.pairs::<String, Table>() {
+            let (name, table) = pair.context("Profiles have to be tables")?;
+            if !is_valid_name(&name) {
+                bail!(
+                    "Invalid profile name `{}`, it can't be `gen` or contain `/` or `..`",
+                    name
+                );
+            }
+            tables.insert(name, table);
+        }
+    }
+
+    let mut profiles = HashMap::new();
+    for name in [DEV_PROFILE, RELEASE_PROFILE] {
+        resolve(name, &tables, &mut profiles, &mut Vec::new())?;
+    }
+    for name in tables.keys() {
+        resolve(name, &tables, &mut profiles, &mut Vec::new())?;
+    }
+    Ok(profiles)
+}
+
+/// Profiles are directories in `build/`, next to the
+/// generated sources in [`super::codegen::GEN_DIR`]
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "gen" && !name.contains(['/', '\\']) && !name.contains("..")
+}
+
+fn resolve(
+    name: &str,
+    tables: &HashMap<String, Table>,
+    profiles: &mut HashMap<String, Profile>,
+    stack: &mut Vec<String>,
+) -> anyhow::Result<Profile> {
+    if let Some(profile) = profiles.get(name) {
+        return Ok(profile.clone());
+    }
+    if stack.iter().any(|elem| elem == name) {
+        bail!("Profile `{}` inherits from itself", name);
+    }
+
+    let table = tables.get(name);
+    let inherits: Option<String> = match table {
+        Some(table) => table.get("inherits")?,
+        None => None,
+    };
+
+    stack.push(name.to_string());
+    let mut profile = match (name, inherits) {
+        (_, Some(parent)) => {
+            if !tables.contains_key(&parent) && parent != DEV_PROFILE && parent != RELEASE_PROFILE {
+                bail!(
+                    "Profile `{}` inherits from unknown profile `{}`",
+                    name,
+                    parent
+                );
+            }
+            resolve(&parent, tables, profiles, stack)?
+        }
+        (DEV_PROFILE, None) => Profile::dev(),
+        (RELEASE_PROFILE, None) => Profile::release(),
+        (_, None) => resolve(DEV_PROFILE, tables, profiles, stack)?,
+    };
+    stack.pop();
+
+    if let Some(table) = table {
+        profile.apply(name, table)?;
+    }
+    profiles.insert(name.to_string(), profile.clone());
+    Ok(profile)
+}
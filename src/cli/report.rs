@@ -0,0 +1,305 @@
+//! Machine readable reports of `surtur test`,
+//! JUnit XML for CI dashboards and JSON for
+//! everything else
+
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use anyhow::bail;
+
+use crate::util;
+
+use super::harness::{self, Outcome, Report, TestResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable output on the terminal
+    Pretty,
+    Junit,
+    Json,
+}
+
+impl Format {
+    pub const NAMES: [&'static str; 3] = ["pretty", "junit", "json"];
+
+    /// The format of a report file, JSON for
+    /// `.json` files and JUnit XML otherwise
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext == "json" => Format::Json,
+            _ => Format::Junit,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(Format::Pretty),
+            "junit" => Ok(Format::Junit),
+            "json" => Ok(Format::Json),
+            _ => bail!(
+                "Invalid report format `{}`, expected one of: {}",
+                s,
+                Format::NAMES.join(", ")
+            ),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Format::Pretty => "pretty",
+            Format::Junit => "junit",
+            Format::Json => "json",
+        })
+    }
+}
+
+/// Render the report of the tests of the project `name`.
+/// Files of the test cases are relative to `root_dir`.
+/// The pretty format is printed while running, so there is nothing to render
+pub fn render(report: &Report, format: Format, name: &str, root_dir: &Path) -> Option<String> {
+    match format {
+        Format::Pretty => None,
+        Format::Junit => Some(junit(report, name, root_dir)),
+        Format::Json => Some(json(report, name, root_dir)),
+    }
+}
+
+fn junit(report: &Report, name: &str, root_dir: &Path) -> String {
+    let attrs = format!(
+        "name={} tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\"",
+        util::xml_str(name),
+        report.results.len(),
+        report.failed() + report.timed_out(),
+        report.crashed(),
+        report.skipped(),
+        report.duration.as_secs_f64()
+    );
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!("<testsuites {}>\n", attrs));
+    xml.push_str(&format!("  <testsuite {}>\n", attrs));
+    for result in &report.results {
+        xml.push_str(&format!(
+            "    <testcase name={} classname={} time=\"{:.3}\">\n",
+            util::xml_str(&result.case.name),
+            util::xml_str(&file_name(result, root_dir)),
+            result.duration.as_secs_f64()
+        ));
+        match (result.outcome, result.message()) {
+            (_, None) => (),
+            (Outcome::Skipped, Some(message)) => {
+                xml.push_str(&format!(
+                    "      <skipped message={}/>\n",
+                    util::xml_str(&message)
+                ));
+            }
+            (outcome, Some(message)) => {
+                // Crashes are errors, the test didn't get to fail on its own
+                let (element, kind) = match outcome {
+                    Outcome::Crashed(_) => ("error", "crashed"),
+                    Outcome::TimedOut => ("failure", "timeout"),
+                    _ => ("failure", "failed"),
+                };
+                xml.push_str(&format!(
+                    "      <{0} message={1} type=\"{2}\">{3}</{0}>\n",
+                    element,
+                    util::xml_str(&message),
+                    kind,
+                    util::xml_text(&result.stderr)
+                ));
+            }
+        }
+        if !result.stdout.is_empty() {
+            xml.push_str(&format!(
+                "      <system-out>{}</system-out>\n",
+                util::xml_text(&result.stdout)
+            ));
+        }
+        if !result.stderr.is_empty() {
+            xml.push_str(&format!(
+                "      <system-err>{}</system-err>\n",
+                util::xml_text(&result.stderr)
+            ));
+        }
+        xml.push_str("    </testcase>\n");
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+fn json(report: &Report, name: &str, root_dir: &Path) -> String {
+    let tests: Vec<String> = report
+        .results
+        .iter()
+        .map(|result| {
+            let (status, code, signal) = match result.outcome {
+                Outcome::Passed => ("passed", None, None),
+                Outcome::Failed(code) => ("failed", Some(code), None),
+                Outcome::Crashed(signal) => ("crashed", None, Some(signal)),
+                Outcome::TimedOut => ("timed_out", None, None),
+                Outcome::Skipped => ("skipped", None, None),
+            };
+            format!(
+                concat!(
+                    "    {{\n",
+                    "      \"name\": {},\n",
+                    "      \"file\": {},\n",
+                    "      \"status\": \"{}\",\n",
+                    "      \"exit_code\": {},\n",
+                    "      \"signal\": {},\n",
+                    "      \"signal_name\": {},\n",
+                    "      \"duration\": {:.3},\n",
+                    "      \"message\": {},\n",
+                    "      \"stdout\": {},\n",
+                    "      \"stderr\": {}\n",
+                    "    }}"
+                ),
+                util::json_str(&result.case.name),
+                util::json_str(&file_name(result, root_dir)),
+                status,
+                json_opt(code),
+                json_opt(signal),
+                signal.map_or("null".into(), |signal| {
+                    util::json_str(&harness::signal_name(signal))
+                }),
+                result.duration.as_secs_f64(),
+                result
+                    .message()
+                    .map_or("null".into(), |message| util::json_str(&message)),
+                util::json_str(&result.stdout),
+                util::json_str(&result.stderr)
+            )
+        })
+        .collect();
+    format!(
+        concat!(
+            "{{\n",
+            "  \"name\": {},\n",
+            "  \"passed\": {},\n",
+            "  \"failed\": {},\n",
+            "  \"crashed\": {},\n",
+            "  \"timed_out\": {},\n",
+            "  \"skipped\": {},\n",
+            "  \"filtered\": {},\n",
+            "  \"duration\": {:.3},\n",
+            "  \"tests\": [\n{}\n  ]\n",
+            "}}\n"
+        ),
+        util::json_str(name),
+        report.passed(),
+        report.failed(),
+        report.crashed(),
+        report.timed_out(),
+        report.skipped(),
+        report.filtered,
+        report.duration.as_secs_f64(),
+        tests.join(",\n")
+    )
+}
+
+fn json_opt(val: Option<i32>) -> String {
+    val.map_or("null".into(), |val| val.to_string())
+}
+
+fn file_name(result: &TestResult, root_dir: &Path) -> String {
+    let file = &result.case.file;
+    file.strip_prefix(root_dir)
+        .unwrap_or(file)
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::*;
+    use crate::cli::harness::TestCase;
+
+    fn result(name: &str, outcome: Outcome, stdout: &str, stderr: &str) -> TestResult {
+        TestResult {
+            case: TestCase {
+                name: name.into(),
+                file: PathBuf::from("/proj/src/lib.c"),
+            },
+            outcome,
+            duration: Duration::from_millis(5),
+            stdout: stdout.into(),
+            stderr: stderr.into(),
+        }
+    }
+
+    fn report() -> Report {
+        Report {
+            results: vec![
+                result("passes", Outcome::Passed, "", ""),
+                result("fails", Outcome::Failed(1), "", "assertion failed\n"),
+                result("crashes", Outcome::Crashed(11), "", ""),
+                result("hangs", Outcome::TimedOut, "", ""),
+                result("skipped", Outcome::Skipped, "", ""),
+                result(
+                    "prints",
+                    Outcome::Passed,
+                    "say \"hi\" <&>\x01\n",
+                    "tab\there\x1b[0m",
+                ),
+            ],
+            filtered: 2,
+            duration: Duration::from_millis(30),
+        }
+    }
+
+    #[test]
+    fn junit_outcomes() {
+        let xml = junit(&report(), "proj", Path::new("/proj"));
+        assert!(xml.contains(
+            "<testsuite name=\"proj\" tests=\"6\" failures=\"2\" errors=\"1\" skipped=\"1\""
+        ));
+        assert!(xml.contains("<testcase name=\"passes\" classname=\"src/lib.c\""));
+        assert!(xml.contains(
+            "<failure message=\"assertion failed (exit code 1)\" type=\"failed\">assertion failed\n</failure>"
+        ));
+        assert!(xml.contains("<error message=\"killed by "));
+        assert!(xml.contains("type=\"crashed\"></error>"));
+        assert!(xml.contains("<failure message=\"timed out after 0.01s\" type=\"timeout\">"));
+        assert!(xml.contains("<skipped message=\"not run, the global timeout ran out\"/>"));
+        // Passed tests have no failure element
+        let passes = xml.split("</testcase>").next().unwrap();
+        assert!(passes.ends_with("time=\"0.005\">\n    "), "{}", passes);
+    }
+
+    #[test]
+    fn junit_escapes_output() {
+        let xml = junit(&report(), "proj", Path::new("/proj"));
+        assert!(xml.contains("<system-out>say \"hi\" &lt;&amp;&gt;\n</system-out>"));
+        assert!(xml.contains("<system-err>tab\there[0m</system-err>"));
+    }
+
+    #[test]
+    fn json_outcomes() {
+        let json = json(&report(), "proj", Path::new("/proj"));
+        for status in ["passed", "failed", "crashed", "timed_out", "skipped"] {
+            assert!(
+                json.contains(&format!("\"status\": \"{}\"", status)),
+                "{}",
+                status
+            );
+        }
+        assert!(json.contains("\"exit_code\": 1,"));
+        assert!(json.contains("\"signal\": 11,"));
+        assert!(json.contains("\"filtered\": 2,"));
+        assert!(json.contains("\"file\": \"src/lib.c\""));
+        assert!(json.contains("\"message\": \"assertion failed (exit code 1)\""));
+    }
+
+    #[test]
+    fn json_escapes_output() {
+        let json = json(&report(), "proj", Path::new("/proj"));
+        assert!(json.contains(r#""stdout": "say \"hi\" <&>\u0001\n","#));
+        assert!(json.contains(r#""stderr": "tab\there\u001b[0m""#));
+    }
+}
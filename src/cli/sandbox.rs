@@ -0,0 +1,183 @@
+//! Restricted lua states for `project.lua` and `project.lock`.
+//! Both come with the project, so running `surtur build` in a
+//! cloned repository or reading the config of a dependency must
+//! not run arbitrary code. Only the parts of the standard library
+//! that can't touch the system are available, and evaluation is
+//! limited in memory and instructions. Build scripts are the only
+//! lua code that runs unrestricted, see [`super::trust`]
+
+use std::cell::Cell;
+
+use anyhow::Context;
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Table, Value};
+
+/// Memory a config may use
+pub const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Instructions the lua code of a config may take, including
+/// metamethods and finalizers that run while it is read
+pub const INSTRUCTION_LIMIT: u64 = 100_000_000;
+
+/// The instruction hook is called after this many instructions
+const HOOK_INTERVAL: u32 = 10_000;
+
+/// Functions of `os` that only read the time and the environment
+const OS_FUNCTIONS: [&str; 5] = ["clock", "date", "difftime", "getenv", "time"];
+
+/// Create a lua state without `io`, `package`, `debug`, the
+/// parts of `os` that change the system and the functions
+/// that load files or bytecode. The state is limited in
+/// memory and instructions
+pub fn new() -> anyhow::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::COROUTINE
+            | StdLib::TABLE
+            | StdLib::STRING
+            | StdLib::UTF8
+            | StdLib::MATH
+            | StdLib::OS,
+        LuaOptions::new(),
+    )
+    .context("Failed to create the lua state")?;
+    lua.set_memory_limit(MEMORY_LIMIT)
+        .context("Failed to limit the memory of the lua state")?;
+    restrict(&lua).context("Failed to restrict the lua state")?;
+    limit(&lua);
+    Ok(lua)
+}
+
+/// Remove the functions of the loaded libraries that aren't safe
+fn restrict(lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    let os: Table = globals.get("os")?;
+    let safe_os = lua.create_table()?;
+    for name in OS_FUNCTIONS {
+        safe_os.set(name, os.get::<_, Value>(name)?)?;
+    }
+    globals.set("os", safe_os)?;
+    globals.set("dofile", Value::Nil)?;
+    globals.set("loadfile", Value::Nil)?;
+
+    // `load` only accepts text, bytecode can break out of the sandbox.
+    // Finalizers run without debug hooks, so they would escape the
+    // instruction limit, and `setmetatable` is the only way to add them
+    let (load_text, setmetatable): (mlua::Function, mlua::Function) = lua
+        .load(
+            r##"
+            local load, setmetatable, rawget, error = load, setmetatable, rawget, error
+            local function load_text(chunk, name, _, ...)
+                -- An explicit nil environment differs from none
+                if select("#", ...) > 0 then
+                    return load(chunk, name, "t", ...)
+                end
+                return load(chunk, name, "t")
+            end
+            local function setmetatable_safe(t, mt)
+                if type(mt) == "table" and rawget(mt, "__gc") ~= nil then
+                    error("`__gc` metamethods are not available in the config", 2)
+                end
+                return setmetatable(t, mt)
+            end
+            return load_text, setmetatable_safe
+            "##,
+        )
+        .eval()?;
+    globals.set("load", load_text)?;
+    globals.set("setmetatable", setmetatable)
+}
+
+/// Evaluate `src` in `lua`, `name` is
+/// the name of the file in error messages
+pub fn eval(lua: &Lua, name: &str, src: &str) -> anyhow::Result<()> {
+    lua.load(src)
+        .set_name(name)
+        .exec()
+        .context(format!("Failed to evaluate {}", name))
+}
+
+/// Run `f` without the instruction limit, for functions of the
+/// config that the user runs explicitly, like custom commands
+pub fn unlimited<R>(lua: &Lua, f: impl FnOnce() -> R) -> R {
+    lua.remove_hook();
+    let result = f();
+    limit(lua);
+    result
+}
+
+/// Fail once the state ran more than [`INSTRUCTION_LIMIT`] instructions.
+/// The limit stays for the lifetime of the state, metamethods
+/// and finalizers that run while the config is read count too
+fn limit(lua: &Lua) {
+    let executed = Cell::new(0u64);
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+        move |_, _| {
+            executed.set(executed.get() + u64::from(HOOK_INTERVAL));
+            if executed.get() > INSTRUCTION_LIMIT {
+                return Err(mlua::Error::runtime(format!(
+                    "exceeded the limit of {} instructions",
+                    INSTRUCTION_LIMIT
+                )));
+            }
+            Ok(())
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+    use crate::cli::lock::Lockfile;
+
+    /// Snippets that try to leave the sandbox or exhaust its limits
+    const ESCAPES: [&str; 9] = [
+        "io.open('/etc/passwd')",
+        "os.execute('true')",
+        "require('os')",
+        "dofile('/etc/passwd')",
+        "loadfile('/etc/passwd')",
+        "assert(load(string.dump(function() end)))",
+        "setmetatable({}, { __gc = function() end })",
+        "while true do end",
+        "local s = string.rep('x', 128 * 1024 * 1024)",
+    ];
+
+    fn run(src: &str) -> anyhow::Result<()> {
+        eval(&new()?, "test.lua", src)
+    }
+
+    #[test]
+    fn safe_functions_are_available() {
+        run("assert(os.time() > 0 and os.getenv ~= nil)").unwrap();
+        run("assert(load('return 1 + 1')() == 2)").unwrap();
+        run("setmetatable({}, { __index = function() return 1 end })").unwrap();
+    }
+
+    #[test]
+    fn escapes_fail() {
+        for src in ESCAPES {
+            assert!(run(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let err = format!("{:#}", run("while true do end").unwrap_err());
+        assert!(err.contains("instructions"), "{}", err);
+        let err = format!("{:#}", run(ESCAPES[8]).unwrap_err());
+        assert!(err.contains("memory"), "{}", err);
+    }
+
+    #[test]
+    fn lockfile_is_sandboxed() {
+        let dir = env::temp_dir().join(format!("surtur-sandbox-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for src in ESCAPES {
+            fs::write(dir.join("project.lock"), format!("{}\nLock = {{}}\n", src)).unwrap();
+            assert!(Lockfile::read(&dir).is_err(), "{}", src);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
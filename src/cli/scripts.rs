@@ -1,15 +1,229 @@
-use std::path::PathBuf;
+//! Build scripts from the `Scripts` table of the config.
+//! Scripts get a `surtur` table with information about
+//! the build and functions to run commands, access files
+//! and emit directives that change the build, like
+//! additional cflags or generated source files
 
-use mlua::Lua;
+use std::{
+    cell::RefCell,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    rc::Rc,
+};
+
+use anyhow::{bail, Context};
+use mlua::{Lua, Table, Value};
+
+use super::{codegen, config::string_list, features};
 
 pub struct ScriptManager {
-    pre_scripts: Vec<PathBuf>,
-    post_scripts: Vec<PathBuf>,
+    pre_scripts: Vec<Script>,
+    post_scripts: Vec<Script>,
     lua_ctx: Lua,
 }
 
+/// A build script with the files it declares to read and write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Script {
+    pub path: PathBuf,
+    /// Files the script reads, relative to the
+    /// project root. Patterns like `spec/*.json` are expanded
+    pub inputs: Vec<String>,
+    /// Files the script writes, relative to the project root
+    pub outputs: Vec<PathBuf>,
+}
+
+impl Script {
+    /// Parse an entry of `Scripts.pre` or `Scripts.post`, either
+    /// the path of the script or `{ "gen.lua", inputs = {}, outputs = {} }`
+    pub fn parse(val: Value) -> anyhow::Result<Self> {
+        let table = match val {
+            Value::String(path) => {
+                return Ok(Self {
+                    path: PathBuf::from(path.to_str()?),
+                    inputs: Vec::new(),
+                    outputs: Vec::new(),
+                })
+            }
+            Value::Table(table) => table,
+            val => bail!("A script has to be a path or a table, found: {:?}", val),
+        };
+        let path: String = table
+            .get(1)
+            .context("A script table has to start with the path of the script")?;
+        let mut script = Self {
+            path: PathBuf::from(&path),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        };
+        for pair in table.pairs::<Value, Value>() {
+            let (key, val) = pair.context(format!("Invalid entry in script `{}`", path))?;
+            match key {
+                Value::Integer(1) => (),
+                Value::String(key) if key == "inputs" => {
+                    script.inputs = string_list("script", &path, "inputs", val)?
+                }
+                Value::String(key) if key == "outputs" => {
+                    script.outputs = string_list("script", &path, "outputs", val)?
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect()
+                }
+                key => bail!("Invalid key in script `{}`: {:?}", path, key),
+            }
+        }
+        Ok(script)
+    }
+}
+
+/// Information about the build that scripts can query
+pub struct ScriptEnv<'a> {
+    pub root_dir: &'a Path,
+    pub name: &'a str,
+    pub version: &'a str,
+    pub profile: &'a str,
+    /// Triple of the target, if cross compiling
+    pub target: Option<&'a str>,
+    /// Build directory of the profile and target
+    pub out_dir: PathBuf,
+}
+
+/// Directives emitted by build scripts that change the build
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Directives {
+    pub cflags: Vec<String>,
+    /// Macros, either `NAME` or `NAME=VALUE`
+    pub defines: Vec<String>,
+    /// Libraries to link, passed as `-l<name>` or as a path
+    pub link_libs: Vec<String>,
+    /// Generated source files that are compiled with the project
+    pub sources: Vec<PathBuf>,
+    /// Files the scripts depend on
+    pub rerun_if_changed: Vec<PathBuf>,
+}
+
+impl Directives {
+    /// Flags passed to the compiler
+    pub fn cflags(&self) -> Vec<String> {
+        let mut flags = self.cflags.clone();
+        flags.extend(features::define_flags(&self.defines));
+        flags
+    }
+
+    /// Flags passed to the linker
+    pub fn ldflags(&self) -> Vec<String> {
+        self.link_libs
+            .iter()
+            .map(|lib| {
+                if lib.contains('/') {
+                    lib.clone()
+                } else {
+                    format!("-l{}", lib)
+                }
+            })
+            .collect()
+    }
+
+    fn extend(&mut self, other: Directives) {
+        self.cflags.extend(other.cflags);
+        self.defines.extend(other.defines);
+        self.link_libs.extend(other.link_libs);
+        self.sources.extend(other.sources);
+        self.rerun_if_changed.extend(other.rerun_if_changed);
+    }
+}
+
+/// What a script did while it ran, recorded through the `surtur` table
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Record {
+    directives: Directives,
+    /// Files read with `surtur.read_file`
+    reads: Vec<PathBuf>,
+    /// Files written with `surtur.write_file`
+    writes: Vec<PathBuf>,
+    /// Patterns passed to `surtur.glob`
+    globs: Vec<String>,
+}
+
+/// The record of the last run of a script and the fingerprint
+/// of everything it depended on, stored in `<build dir>/scripts/`
+struct Cache {
+    fingerprint: u64,
+    record: Record,
+}
+
+impl Cache {
+    fn read(path: &Path) -> Option<Self> {
+        let content = fs::read_to_string(path).ok()?;
+        let mut fingerprint = None;
+        let mut record = Record::default();
+        for line in content.lines() {
+            let (key, val) = line.split_once(' ')?;
+            match key {
+                "fingerprint" => fingerprint = val.parse().ok(),
+                "read" => record.reads.push(val.into()),
+                "write" => record.writes.push(val.into()),
+                "glob" => record.globs.push(val.into()),
+                "cflag" => record.directives.cflags.push(val.into()),
+                "define" => record.directives.defines.push(val.into()),
+                "link" => record.directives.link_libs.push(val.into()),
+                "source" => record.directives.sources.push(val.into()),
+                "rerun_if_changed" => record.directives.rerun_if_changed.push(val.into()),
+                _ => return None,
+            }
+        }
+        Some(Self {
+            fingerprint: fingerprint?,
+            record,
+        })
+    }
+
+    /// Whether the script can be skipped, its outputs exist
+    /// and nothing it depends on changed since it last ran
+    fn is_fresh(&self, script: &Script, env: &ScriptEnv) -> bool {
+        let outputs_exist = script
+            .outputs
+            .iter()
+            .map(|output| env.root_dir.join(output))
+            .chain(self.record.writes.iter().cloned())
+            .all(|output| output.exists());
+        outputs_exist && fingerprint(script, env, &self.record) == Some(self.fingerprint)
+    }
+
+    fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let record = &self.record;
+        let directives = &record.directives;
+        let mut content = format!("fingerprint {}\n", self.fingerprint);
+        let paths = |key: &str, paths: &[PathBuf]| -> String {
+            paths
+                .iter()
+                .map(|path| format!("{} {}\n", key, path.display()))
+                .collect()
+        };
+        let strings = |key: &str, vals: &[String]| -> String {
+            vals.iter()
+                .map(|val| format!("{} {}\n", key, val))
+                .collect()
+        };
+        content.push_str(&paths("read", &record.reads));
+        content.push_str(&paths("write", &record.writes));
+        content.push_str(&strings("glob", &record.globs));
+        content.push_str(&strings("cflag", &directives.cflags));
+        content.push_str(&strings("define", &directives.defines));
+        content.push_str(&strings("link", &directives.link_libs));
+        content.push_str(&paths("source", &directives.sources));
+        content.push_str(&paths("rerun_if_changed", &directives.rerun_if_changed));
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("Failed to create script cache directory")?;
+        }
+        fs::write(path, content).context("Failed to write script cache")
+    }
+}
+
 impl ScriptManager {
-    pub fn new(pre_scripts: Vec<PathBuf>, post_scripts: Vec<PathBuf>) -> Self {
+    pub fn new(pre_scripts: Vec<Script>, post_scripts: Vec<Script>) -> Self {
         Self {
             pre_scripts,
             post_scripts,
@@ -17,17 +231,588 @@ impl ScriptManager {
         }
     }
 
-    pub fn pre_exec(&self) -> mlua::Result<()> {
-        for script in &self.pre_scripts {
-            self.lua_ctx.load(script.as_path()).exec()?;
+    /// All scripts, the ones that run before the build first
+    pub fn scripts(&self) -> impl Iterator<Item = &Script> {
+        self.pre_scripts.iter().chain(&self.post_scripts)
+    }
+
+    /// Run the scripts before the build and
+    /// collect the directives they emit.
+    /// Unless `rerun` is set, scripts whose inputs didn't
+    /// change are skipped and their last directives are used
+    pub fn pre_exec(&self, env: &ScriptEnv, rerun: bool) -> anyhow::Result<Directives> {
+        self.exec("pre", &self.pre_scripts, env, rerun)
+    }
+
+    /// Run the scripts after the build. Directives
+    /// are ignored, the build is already done
+    pub fn post_exec(&self, env: &ScriptEnv, rerun: bool) -> anyhow::Result<()> {
+        self.exec("post", &self.post_scripts, env, rerun)
+            .map(|_| ())
+    }
+
+    fn exec(
+        &self,
+        stage: &str,
+        scripts: &[Script],
+        env: &ScriptEnv,
+        rerun: bool,
+    ) -> anyhow::Result<Directives> {
+        let cache_dir = env.root_dir.join(&env.out_dir).join("scripts");
+        let mut directives = Directives::default();
+        for script in scripts {
+            let cache_name: String = script
+                .path
+                .to_string_lossy()
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '.' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            let cache_path = cache_dir.join(format!("{}-{}", stage, cache_name));
+
+            let cached = match rerun {
+                true => None,
+                false => Cache::read(&cache_path),
+            };
+            let record = match cached.filter(|cache| cache.is_fresh(script, env)) {
+                Some(cache) => cache.record,
+                None => {
+                    let record = self.run_script(script, env)?;
+                    // Scripts without inputs always run, there
+                    // is no way to know when they are out of date
+                    if let Some(fingerprint) = fingerprint(script, env, &record) {
+                        Cache {
+                            fingerprint,
+                            record: record.clone(),
+                        }
+                        .write(&cache_path)?;
+                    }
+                    record
+                }
+            };
+            directives.extend(record.directives);
+        }
+        Ok(directives)
+    }
+
+    fn run_script(&self, script: &Script, env: &ScriptEnv) -> anyhow::Result<Record> {
+        let record = Rc::new(RefCell::new(Record::default()));
+        let api =
+            api(&self.lua_ctx, env, &record).context("Failed to create the `surtur` table")?;
+        self.lua_ctx.globals().set("surtur", api)?;
+
+        self.lua_ctx
+            .load(script.path.as_path())
+            .exec()
+            .context(format!(
+                "Failed to run build script: {}",
+                script.path.display()
+            ))?;
+        let record = record.borrow().clone();
+        Ok(record)
+    }
+}
+
+/// Set the `surtur` table in `lua`, for Lua code
+/// that isn't a build script, like custom commands.
+/// Directives are ignored
+pub fn set_api(lua: &Lua, env: &ScriptEnv) -> mlua::Result<()> {
+    let record = Rc::new(RefCell::new(Record::default()));
+    lua.globals().set("surtur", api(lua, env, &record)?)
+}
+
+/// Create the `surtur` table. Relative paths passed to
+/// its functions are relative to the project root
+fn api<'lua>(
+    lua: &'lua Lua,
+    env: &ScriptEnv,
+    record: &Rc<RefCell<Record>>,
+) -> mlua::Result<Table<'lua>> {
+    let api = lua.create_table()?;
+
+    let project = lua.create_table()?;
+    project.set("name", env.name)?;
+    project.set("version", env.version)?;
+    project.set("profile", env.profile)?;
+    project.set("target", env.target)?;
+    project.set("root", env.root_dir.to_string_lossy())?;
+    project.set("out_dir", env.root_dir.join(&env.out_dir).to_string_lossy())?;
+    project.set(
+        "gen_dir",
+        env.root_dir.join(codegen::GEN_DIR).to_string_lossy(),
+    )?;
+    api.set("project", project)?;
+
+    let root = env.root_dir.to_path_buf();
+
+    // surtur.run(program, { args }) -> { success, code, stdout, stderr }
+    let dir = root.clone();
+    api.set(
+        "run",
+        lua.create_function(move |lua, (program, args): (String, Option<Vec<String>>)| {
+            let output = Command::new(&program)
+                .args(args.unwrap_or_default())
+                .current_dir(&dir)
+                .output()
+                .map_err(|err| {
+                    mlua::Error::runtime(format!("Failed to run `{}`: {}", program, err))
+                })?;
+            let result = lua.create_table()?;
+            result.set("success", output.status.success())?;
+            result.set("code", output.status.code())?;
+            result.set("stdout", String::from_utf8_lossy(&output.stdout))?;
+            result.set("stderr", String::from_utf8_lossy(&output.stderr))?;
+            Ok(result)
+        })?,
+    )?;
+
+    let r = record.clone();
+    let dir = root.clone();
+    api.set(
+        "read_file",
+        lua.create_function(move |_, path: String| {
+            let content = fs::read_to_string(dir.join(&path))
+                .map_err(|err| mlua::Error::runtime(format!("Failed to read {}: {}", path, err)))?;
+            r.borrow_mut().reads.push(dir.join(path));
+            Ok(content)
+        })?,
+    )?;
+
+    let r = record.clone();
+    let dir = root.clone();
+    api.set(
+        "write_file",
+        lua.create_function(move |_, (path, content): (String, mlua::String)| {
+            let path = dir.join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(mlua::Error::external)?;
+            }
+            fs::write(&path, content.as_bytes()).map_err(|err| {
+                mlua::Error::runtime(format!("Failed to write {}: {}", path.display(), err))
+            })?;
+            r.borrow_mut().writes.push(path);
+            Ok(())
+        })?,
+    )?;
+
+    let r = record.clone();
+    let dir = root.clone();
+    api.set(
+        "glob",
+        lua.create_function(move |_, pattern: String| {
+            let files = glob(&dir, &pattern);
+            r.borrow_mut().globs.push(pattern);
+            Ok(files)
+        })?,
+    )?;
+
+    let r = record.clone();
+    api.set(
+        "cflag",
+        lua.create_function(move |_, flag: String| {
+            r.borrow_mut().directives.cflags.push(flag);
+            Ok(())
+        })?,
+    )?;
+
+    let r = record.clone();
+    api.set(
+        "define",
+        lua.create_function(move |_, (name, value): (String, Option<String>)| {
+            r.borrow_mut().directives.defines.push(match value {
+                Some(value) => format!("{}={}", name, value),
+                None => name,
+            });
+            Ok(())
+        })?,
+    )?;
+
+    let r = record.clone();
+    let dir = root.clone();
+    api.set(
+        "link",
+        lua.create_function(move |_, lib: String| {
+            // Paths to libraries are relative to the root like every path
+            let lib = match lib.contains('/') {
+                true => dir.join(lib).to_string_lossy().to_string(),
+                false => lib,
+            };
+            r.borrow_mut().directives.link_libs.push(lib);
+            Ok(())
+        })?,
+    )?;
+
+    let r = record.clone();
+    let dir = root.clone();
+    api.set(
+        "source",
+        lua.create_function(move |_, path: String| {
+            r.borrow_mut().directives.sources.push(dir.join(path));
+            Ok(())
+        })?,
+    )?;
+
+    let r = record.clone();
+    api.set(
+        "rerun_if_changed",
+        lua.create_function(move |_, path: String| {
+            r.borrow_mut()
+                .directives
+                .rerun_if_changed
+                .push(root.join(path));
+            Ok(())
+        })?,
+    )?;
+
+    Ok(api)
+}
+
+/// Fingerprint of the script, the build it runs for and the
+/// content of its inputs. There is none if the script doesn't
+/// declare or record any input
+fn fingerprint(script: &Script, env: &ScriptEnv, record: &Record) -> Option<u64> {
+    let mut inputs: Vec<PathBuf> = script
+        .inputs
+        .iter()
+        .flat_map(|input| match input.contains(['*', '?']) {
+            true => glob(env.root_dir, input),
+            false => vec![input.clone()],
+        })
+        .map(|input| env.root_dir.join(input))
+        .collect();
+    inputs.extend(record.reads.iter().cloned());
+    inputs.extend(record.directives.rerun_if_changed.iter().cloned());
+    if inputs.is_empty() && record.globs.is_empty() {
+        return None;
+    }
+
+    let mut hash = Fnv::new();
+    for field in [env.name, env.version, env.profile, env.target.unwrap_or("")] {
+        hash.field(field.as_bytes());
+    }
+    hash.file(&env.root_dir.join(&script.path));
+    for input in inputs {
+        hash.field(input.to_string_lossy().as_bytes());
+        hash.file(&input);
+    }
+    // Globs depend on which files exist, not on their content
+    for pattern in &record.globs {
+        hash.field(pattern.as_bytes());
+        let matches = glob(env.root_dir, pattern);
+        hash.field(&(matches.len() as u64).to_le_bytes());
+        for path in matches {
+            hash.field(path.as_bytes());
+        }
+    }
+    Some(hash.0)
+}
+
+/// 64 bit FNV-1a hash of fingerprints. They are stored in the
+/// build directory, so unlike `DefaultHasher` the hash has to
+/// stay the same between Rust releases
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Add a field, prefixed with its length
+    /// so fields can't run into each other
+    fn field(&mut self, bytes: &[u8]) {
+        for &byte in (bytes.len() as u64).to_le_bytes().iter().chain(bytes) {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// Add the content of a file, missing files differ from empty ones
+    fn file(&mut self, path: &Path) {
+        match fs::read(path) {
+            Ok(content) => {
+                self.field(b"file");
+                self.field(&content);
+            }
+            Err(_) => self.field(b"missing"),
+        }
+    }
+}
+
+/// Files below `root` that match `pattern`, relative to `root`.
+/// `*` and `?` match within a path component, `**` matches
+/// any number of components
+pub fn glob(root: &Path, pattern: &str) -> Vec<String> {
+    let pattern: Vec<&str> = pattern.split('/').filter(|part| !part.is_empty()).collect();
+    // Only walk the directory that the pattern starts with
+    let literal = pattern
+        .iter()
+        .take_while(|part| !part.contains(['*', '?']))
+        .count()
+        .min(pattern.len().saturating_sub(1));
+    // and only as deep as the pattern goes without `**`
+    let max_depth = match pattern.contains(&"**") {
+        true => usize::MAX,
+        false => pattern.len(),
+    };
+
+    let mut matches = Vec::new();
+    let mut pending: Vec<Vec<String>> = vec![pattern[..literal]
+        .iter()
+        .map(|part| part.to_string())
+        .collect()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(root.join(dir.iter().collect::<PathBuf>())) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let mut parts = dir.clone();
+            parts.push(entry.file_name().to_string_lossy().to_string());
+            if entry.file_type().is_ok_and(|ty| ty.is_dir()) {
+                // The files in it are one component deeper
+                if parts.len() < max_depth {
+                    pending.push(parts);
+                }
+                continue;
+            }
+            let part_refs: Vec<&str> = parts.iter().map(String::as_str).collect();
+            if match_parts(&pattern, &part_refs) {
+                matches.push(parts.join("/"));
+            }
+        }
+    }
+    matches.sort();
+    matches
+}
+
+fn match_parts(pattern: &[&str], parts: &[&str]) -> bool {
+    match (pattern.first(), parts.first()) {
+        (None, None) => true,
+        (Some(&"**"), _) => {
+            match_parts(&pattern[1..], parts)
+                || (!parts.is_empty() && match_parts(pattern, &parts[1..]))
+        }
+        (Some(pat), Some(part)) => {
+            match_part(pat.as_bytes(), part.as_bytes()) && match_parts(&pattern[1..], &parts[1..])
+        }
+        _ => false,
+    }
+}
+
+fn match_part(pattern: &[u8], part: &[u8]) -> bool {
+    match (pattern.first(), part.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            match_part(&pattern[1..], part) || (!part.is_empty() && match_part(pattern, &part[1..]))
         }
-        Ok(())
+        (Some(b'?'), Some(_)) => match_part(&pattern[1..], &part[1..]),
+        (Some(pat), Some(c)) => pat == c && match_part(&pattern[1..], &part[1..]),
+        _ => false,
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
 
-    pub fn post_exec(&self) -> mlua::Result<()> {
-        for script in &self.post_scripts {
-            self.lua_ctx.load(script.as_path()).exec()?;
+    use super::*;
+
+    fn project(test: &str, files: &[&str]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("surtur-scripts-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
         }
-        Ok(())
+        dir
+    }
+
+    fn env(root_dir: &Path) -> ScriptEnv<'_> {
+        ScriptEnv {
+            root_dir,
+            name: "proj",
+            version: "0.1.0",
+            profile: "dev",
+            target: None,
+            out_dir: PathBuf::from("build/dev"),
+        }
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let root = project("cache", &[]);
+        let cache = Cache {
+            fingerprint: u64::MAX,
+            record: Record {
+                directives: Directives {
+                    cflags: vec!["-O1".into(), "-I include dir".into()],
+                    defines: vec!["A=1".into()],
+                    link_libs: vec!["m".into()],
+                    sources: vec![root.join("build/gen/a.c")],
+                    rerun_if_changed: vec![root.join("spec.json")],
+                },
+                reads: vec![root.join("in.txt")],
+                writes: vec![root.join("out.txt")],
+                globs: vec!["spec/*.json".into()],
+            },
+        };
+        let path = root.join("build/dev/scripts/pre-gen.lua");
+        cache.write(&path).unwrap();
+        let read = Cache::read(&path).unwrap();
+        assert_eq!(read.fingerprint, cache.fingerprint);
+        assert_eq!(read.record, cache.record);
+
+        fs::write(&path, "fingerprint 1\nunknown key\n").unwrap();
+        assert!(Cache::read(&path).is_none());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn cache_freshness() {
+        let root = project("fresh", &["gen.lua", "spec/a.json"]);
+        let env = env(&root);
+        let script = Script {
+            path: PathBuf::from("gen.lua"),
+            inputs: vec!["spec/*.json".into()],
+            outputs: vec![PathBuf::from("out.c")],
+        };
+        let record = Record {
+            globs: vec!["spec/*.json".into()],
+            ..Record::default()
+        };
+        let cache = Cache {
+            fingerprint: fingerprint(&script, &env, &record).unwrap(),
+            record,
+        };
+
+        // The declared output is missing
+        assert!(!cache.is_fresh(&script, &env));
+        fs::write(root.join("out.c"), "").unwrap();
+        assert!(cache.is_fresh(&script, &env));
+
+        // An input changed
+        fs::write(root.join("spec/a.json"), "{}").unwrap();
+        assert!(!cache.is_fresh(&script, &env));
+        fs::write(root.join("spec/a.json"), "spec/a.json").unwrap();
+        assert!(cache.is_fresh(&script, &env));
+
+        // The files that match a glob changed
+        fs::write(root.join("spec/b.json"), "").unwrap();
+        assert!(!cache.is_fresh(&script, &env));
+        fs::remove_file(root.join("spec/b.json")).unwrap();
+        assert!(cache.is_fresh(&script, &env));
+
+        // The script itself changed
+        fs::write(root.join("gen.lua"), "-- changed").unwrap();
+        assert!(!cache.is_fresh(&script, &env));
+
+        // Scripts without inputs have no fingerprint
+        let script = Script {
+            inputs: Vec::new(),
+            ..script
+        };
+        assert!(fingerprint(&script, &env, &Record::default()).is_none());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn scripts_are_skipped_unless_rerun() {
+        let root = project("rerun", &["input.txt"]);
+        let runs = root.join("runs");
+        fs::write(
+            root.join("gen.lua"),
+            format!(
+                concat!(
+                    "local runs = io.open({}, \"a\")\n",
+                    "runs:write(\"x\")\n",
+                    "runs:close()\n",
+                    "surtur.rerun_if_changed(\"input.txt\")\n",
+                    "surtur.cflag(\"-DRAN\")\n",
+                ),
+                crate::util::lua_str(&runs.to_string_lossy())
+            ),
+        )
+        .unwrap();
+        let script = Script {
+            path: root.join("gen.lua"),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        };
+        let sm = ScriptManager::new(vec![script], Vec::new());
+        let env = env(&root);
+        let run = |rerun| sm.pre_exec(&env, rerun).unwrap().cflags;
+
+        assert_eq!(run(false), ["-DRAN"]);
+        // The directives of the last run are used
+        assert_eq!(run(false), ["-DRAN"]);
+        assert_eq!(fs::read_to_string(&runs).unwrap(), "x");
+        // `--rerun-scripts`
+        run(true);
+        assert_eq!(fs::read_to_string(&runs).unwrap(), "xx");
+        fs::write(root.join("input.txt"), "changed").unwrap();
+        run(false);
+        assert_eq!(fs::read_to_string(&runs).unwrap(), "xxx");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn match_components() {
+        assert!(match_part(b"main.c", b"main.c"));
+        assert!(match_part(b"*.c", b"main.c"));
+        assert!(match_part(b"m*n.?", b"main.c"));
+        assert!(match_part(b"*", b""));
+        assert!(!match_part(b"*.c", b"main.h"));
+        assert!(!match_part(b"?", b""));
+        assert!(!match_part(b"main", b"main.c"));
+
+        assert!(match_parts(&["**"], &[]));
+        assert!(match_parts(&["**", "*.c"], &["a", "b", "c.c"]));
+        assert!(match_parts(&["src", "**", "*.c"], &["src", "c.c"]));
+        assert!(match_parts(&["src", "**"], &["src", "a", "b"]));
+        assert!(!match_parts(&["src", "*.c"], &["src", "a", "c.c"]));
+        assert!(!match_parts(&["src", "**", "*.c"], &["lib", "c.c"]));
+    }
+
+    #[test]
+    fn glob_files() {
+        let root = project(
+            "glob",
+            &[
+                "src/a.c",
+                "src/b.h",
+                "src/sub/c.c",
+                "src/sub/deep/d.c",
+                "spec/x.json",
+                "README",
+            ],
+        );
+        assert_eq!(glob(&root, "src/*.c"), ["src/a.c"]);
+        assert_eq!(glob(&root, "src/?.h"), ["src/b.h"]);
+        assert_eq!(glob(&root, "src/sub/deep/d.c"), ["src/sub/deep/d.c"]);
+        assert_eq!(glob(&root, "*/*/c.c"), ["src/sub/c.c"]);
+        assert_eq!(
+            glob(&root, "**/*.c"),
+            ["src/a.c", "src/sub/c.c", "src/sub/deep/d.c"]
+        );
+        assert_eq!(
+            glob(&root, "src/**/*.c"),
+            ["src/a.c", "src/sub/c.c", "src/sub/deep/d.c"]
+        );
+        assert_eq!(
+            glob(&root, "src/**"),
+            ["src/a.c", "src/b.h", "src/sub/c.c", "src/sub/deep/d.c"]
+        );
+        assert_eq!(glob(&root, "**/x.json"), ["spec/x.json"]);
+
+        assert!(glob(&root, "src/*.cpp").is_empty());
+        assert!(glob(&root, "lib/**/*.c").is_empty());
+        assert!(glob(&root, "src/s*/c.h").is_empty());
+        assert!(glob(&root, "*.c").is_empty());
+        fs::remove_dir_all(root).unwrap();
     }
 }
@@ -0,0 +1,144 @@
+//! Cross compilation targets from the `Targets` table of the config.
+//! A target is selected with `--target <triple>` and decides
+//! which toolchain is used, by default the one prefixed with
+//! the triple (`aarch64-linux-gnu-gcc`, `aarch64-linux-gnu-ar`)
+
+use std::{collections::HashMap, env, path::PathBuf};
+
+use anyhow::{bail, Context};
+use mlua::{Table, Value};
+
+use super::config::string_list;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub triple: String,
+    /// Prefix of the toolchain commands, defaults to `<triple>-`
+    /// for gcc. Clang is passed `--target=<triple>` instead
+    pub prefix: Option<String>,
+    pub sysroot: Option<PathBuf>,
+    pub cflags: Vec<String>,
+    pub ldflags: Vec<String>,
+    /// Command that runs programs of the target, like `qemu-aarch64`
+    pub runner: Option<Vec<String>>,
+}
+
+impl Target {
+    pub fn new(triple: &str) -> Self {
+        Self {
+            triple: triple.to_string(),
+            prefix: None,
+            sysroot: None,
+            cflags: Vec::new(),
+            ldflags: Vec::new(),
+            runner: None,
+        }
+    }
+
+    /// Whether programs of the target can run on this machine
+    pub fn is_native(&self) -> bool {
+        self.runs_on(env::consts::ARCH, env::consts::OS)
+    }
+
+    /// Whether programs of the target run on `arch` and `os`, named
+    /// like in [`env::consts`]. Triples use other names for some of
+    /// them, like `arm64` and `darwin` for `aarch64` and `macos`
+    fn runs_on(&self, arch: &str, os: &str) -> bool {
+        let mut parts = self.triple.split('-');
+        let triple_arch = parts.next().unwrap_or_default();
+        let arch_matches = triple_arch == arch
+            || match arch {
+                "aarch64" => triple_arch == "arm64",
+                "x86" => matches!(triple_arch, "i386" | "i486" | "i586" | "i686"),
+                _ => false,
+            };
+        let os_names: &[&str] = match os {
+            "macos" => &["darwin", "macos", "macosx"],
+            "windows" => &["windows", "mingw32"],
+            os => &[os],
+        };
+        // The OS can have a version, like `darwin23.1.0` or `freebsd14.0`
+        arch_matches
+            && parts.any(|part| {
+                os_names.iter().any(|name| {
+                    part.strip_prefix(name).is_some_and(|version| {
+                        version.chars().all(|c| c.is_ascii_digit() || c == '.')
+                    })
+                })
+            })
+    }
+
+    fn parse(triple: &str, table: Table) -> anyhow::Result<Self> {
+        let mut target = Self::new(triple);
+        for pair in table.pairs::<String, Value>() {
+            let (key, val) = pair.context(format!("Invalid entry in target `{}`", triple))?;
+            match key.as_str() {
+                "prefix" => target.prefix = Some(string(triple, &key, val)?),
+                "sysroot" => target.sysroot = Some(string(triple, &key, val)?.into()),
+                "cflags" => target.cflags = string_list("target", triple, &key, val)?,
+                "ldflags" => target.ldflags = string_list("target", triple, &key, val)?,
+                "runner" => {
+                    target.runner = Some(match val {
+                        Value::String(runner) => runner
+                            .to_str()?
+                            .split_whitespace()
+                            .map(String::from)
+                            .collect(),
+                        val => string_list("target", triple, &key, val)?,
+                    })
+                }
+                key => bail!("Invalid key in target `{}`: {}", triple, key),
+            }
+        }
+        Ok(target)
+    }
+}
+
+fn string(target: &str, key: &str, val: Value) -> anyhow::Result<String> {
+    match val {
+        Value::String(val) => Ok(val.to_str()?.to_string()),
+        val => bail!(
+            "`{}` of target `{}` has to be a string, found: {:?}",
+            key,
+            target,
+            val
+        ),
+    }
+}
+
+/// Parse the `Targets` table
+pub fn parse_targets(table: Option<Table>) -> anyhow::Result<HashMap<String, Target>> {
+    let mut targets = HashMap::new();
+    if let Some(table) = table {
+        for pair in table.pairs::<String, Table>() {
+            let (triple, table) = pair.context("Targets have to be tables")?;
+            let target = Target::parse(&triple, table)?;
+            targets.insert(triple, target);
+        }
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn native_triples() {
+        let runs_on = |triple: &str, arch, os| Target::new(triple).runs_on(arch, os);
+        assert!(runs_on("x86_64-unknown-linux-gnu", "x86_64", "linux"));
+        assert!(runs_on("x86_64-linux-gnu", "x86_64", "linux"));
+        assert!(runs_on("aarch64-apple-darwin", "aarch64", "macos"));
+        assert!(runs_on("arm64-apple-macosx14.0.0", "aarch64", "macos"));
+        assert!(runs_on("x86_64-apple-darwin23.1.0", "x86_64", "macos"));
+        assert!(runs_on("i686-pc-windows-gnu", "x86", "windows"));
+        assert!(runs_on("x86_64-w64-mingw32", "x86_64", "windows"));
+        assert!(runs_on("x86_64-unknown-freebsd14.0", "x86_64", "freebsd"));
+
+        assert!(!runs_on("aarch64-linux-gnu", "x86_64", "linux"));
+        assert!(!runs_on("aarch64-apple-darwin", "aarch64", "linux"));
+        assert!(!runs_on("x86_64-unknown-linux-gnu", "x86_64", "macos"));
+        assert!(!runs_on("x86_64-w64-mingw32", "x86_64", "linux"));
+        assert!(!runs_on("x86_64-unknown-linuxish", "x86_64", "linux"));
+    }
+}
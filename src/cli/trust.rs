@@ -0,0 +1,115 @@
+//! Projects that are allowed to run build scripts, generators and commands.
+//! Unlike the config, those run unrestricted, so they only run in
+//! projects that are on the allowlist in `~/.surtur/trusted`.
+//! Projects are added with `surtur trust` or by answering
+//! the prompt before their first build
+
+use std::{
+    env, fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use colored::Colorize;
+
+use crate::global::SURTUR_HOME;
+
+/// File in the surtur home directory with one trusted project root per line
+pub const TRUST_FILE: &str = "trusted";
+
+fn trust_file() -> anyhow::Result<PathBuf> {
+    Ok(PathBuf::from(env::var(SURTUR_HOME)?).join(TRUST_FILE))
+}
+
+/// Projects are identified by their canonical root directory
+fn project_id(root_dir: &Path) -> anyhow::Result<String> {
+    let root_dir = root_dir.canonicalize().context(format!(
+        "Failed to resolve project directory: {}",
+        root_dir.display()
+    ))?;
+    Ok(root_dir.to_string_lossy().to_string())
+}
+
+fn read_trusted() -> anyhow::Result<Vec<String>> {
+    let path = trust_file()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&path).context("Failed to read trusted projects")?;
+    Ok(content.lines().map(String::from).collect())
+}
+
+fn write_trusted(trusted: &[String]) -> anyhow::Result<()> {
+    let mut content = trusted.join("\n");
+    content.push('\n');
+    fs::write(trust_file()?, content).context("Failed to write trusted projects")
+}
+
+pub fn is_trusted(root_dir: &Path) -> anyhow::Result<bool> {
+    let id = project_id(root_dir)?;
+    Ok(read_trusted()?.contains(&id))
+}
+
+/// Add the project to the allowlist, returns
+/// `false` if it was already trusted
+pub fn trust(root_dir: &Path) -> anyhow::Result<bool> {
+    let id = project_id(root_dir)?;
+    let mut trusted = read_trusted()?;
+    if trusted.contains(&id) {
+        return Ok(false);
+    }
+    trusted.push(id);
+    write_trusted(&trusted)?;
+    Ok(true)
+}
+
+/// Remove the project from the allowlist, returns
+/// `false` if it wasn't trusted
+pub fn revoke(root_dir: &Path) -> anyhow::Result<bool> {
+    let id = project_id(root_dir)?;
+    let mut trusted = read_trusted()?;
+    let len = trusted.len();
+    trusted.retain(|elem| *elem != id);
+    if trusted.len() == len {
+        return Ok(false);
+    }
+    write_trusted(&trusted)?;
+    Ok(true)
+}
+
+/// Make sure the project may run `code`, a description of each
+/// build script, generator and command. Asks whether to trust the
+/// project if it isn't trusted yet and surtur runs in a terminal,
+/// fails otherwise
+pub fn ensure(root_dir: &Path, code: &[String]) -> anyhow::Result<()> {
+    if code.is_empty() || is_trusted(root_dir)? {
+        return Ok(());
+    }
+    let hint = "run `surtur trust` to allow the project to run them";
+    if !io::stdin().is_terminal() {
+        bail!(
+            "The project is not trusted to run build scripts, generators and commands, {}",
+            hint
+        );
+    }
+
+    eprintln!(
+        "{} This project runs code that can access your system:",
+        "Warning:".yellow()
+    );
+    for elem in code {
+        eprintln!("  - {}", elem);
+    }
+    eprint!("Trust {} and run it? [y/N] ", root_dir.display());
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .context("Failed to read the answer")?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => trust(root_dir).map(|_| ()),
+        _ => bail!("The project is not trusted, {}", hint),
+    }
+}
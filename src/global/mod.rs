@@ -1,13 +1,17 @@
 //! Module responsible for global file system operations
 //! like installing dependencies and saving configurations
 
-use std::{env, fs, path::PathBuf};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use dirs::home_dir;
-use git2::Repository;
+use git2::{build::CheckoutBuilder, Oid, Repository};
+use semver::{Version, VersionReq};
 
-use crate::cli::deps::Dependency;
+use crate::cli::deps::{Dependency, GitRef};
 
 pub const SURTUR_HOME: &str = "SURTUR_HOME";
 
@@ -26,18 +30,204 @@ pub(super) fn init_dir() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub(super) fn download_dep(dep: &Dependency, forced: bool) -> anyhow::Result<()> {
-    let url = &dep.origin;
-    let dep_path = PathBuf::from(env::var(SURTUR_HOME)?)
+/// The exact state a dependency was resolved to
+pub struct Resolved {
+    pub tag: Option<String>,
+    pub commit: String,
+}
+
+#[inline(always)]
+fn dep_path(dep: &Dependency) -> anyhow::Result<PathBuf> {
+    Ok(PathBuf::from(env::var(SURTUR_HOME)?)
         .join("deps")
-        .join(dep.name()?);
-    if !dep_path.exists() {
-        if let Err(err) = Repository::clone(url, dep_path) {
-            eprintln!("{}", err);
-        }
-    } else if dep_path.exists() && forced {
+        .join(dep.name()?))
+}
+
+fn open_or_clone(dep: &Dependency, dep_path: &Path) -> anyhow::Result<Repository> {
+    if dep_path.exists() {
+        Repository::open(dep_path)
+            .context(format!("Failed to open dependency: {}", dep_path.display()))
+    } else {
+        let origin = dep
+            .origin()
+            .context(format!("{} is not a git dependency", dep.name()?))?;
+        Repository::clone(origin, dep_path)
+            .context(format!("Failed to clone dependency: {}", origin))
+    }
+}
+
+pub(super) fn download_dep(dep: &Dependency, forced: bool) -> anyhow::Result<Resolved> {
+    let name = dep.name()?;
+    let dep_path = dep_path(dep)?;
+    if dep_path.exists() && forced {
         fs::remove_dir_all(&dep_path)?;
-        Repository::clone(url, dep_path)?;
     }
-    Ok(())
+    let existed = dep_path.exists();
+    let repo = open_or_clone(dep, &dep_path)?;
+    if existed {
+        fetch(&repo).context(format!("Failed to fetch {}", name))?;
+    }
+
+    let (tag, commit) = match (&dep.version, &dep.git_ref) {
+        (Some(req), _) => {
+            let tag = resolve_version(&repo, req)
+                .context(format!("Failed to resolve version of {}", name))?;
+            let commit = find_commit(&repo, &format!("refs/tags/{}", tag))?;
+            (Some(tag), commit)
+        }
+        (None, Some(GitRef::Tag(tag))) => {
+            let commit = find_commit(&repo, &format!("refs/tags/{}", tag))?;
+            (Some(tag.clone()), commit)
+        }
+        (None, Some(GitRef::Branch(branch))) => {
+            let commit = find_commit(&repo, &format!("refs/remotes/origin/{}", branch))?;
+            (None, commit)
+        }
+        (None, Some(GitRef::Rev(rev))) => (None, find_commit(&repo, rev)?),
+        // The default branch of the remote
+        (None, None) => {
+            let commit = find_commit(&repo, "refs/remotes/origin/HEAD")
+                .or_else(|_| find_commit(&repo, "HEAD"))?;
+            (None, commit)
+        }
+    };
+
+    checkout(&repo, commit)?;
+    match (&tag, &dep.git_ref) {
+        (Some(tag), _) => println!("Using {} {}", name, tag),
+        (None, Some(git_ref)) => println!("Using {} {} ({})", name, git_ref, commit),
+        (None, None) => (),
+    }
+
+    Ok(Resolved {
+        tag,
+        commit: commit.to_string(),
+    })
+}
+
+/// Check out the exact commit of a dependency.
+/// The dependency is downloaded if it is missing and
+/// fetched if it doesn't contain the commit yet.
+/// Nothing is touched if the commit is already checked out
+pub(super) fn checkout_commit(dep: &Dependency, commit: &str) -> anyhow::Result<()> {
+    let dep_path = dep_path(dep)?;
+    let repo = open_or_clone(dep, &dep_path)?;
+    let oid = Oid::from_str(commit).context(format!("Invalid commit hash: {}", commit))?;
+
+    if repo.head().ok().and_then(|head| head.target()) == Some(oid) {
+        return Ok(());
+    }
+
+    if repo.find_commit(oid).is_err() {
+        fetch(&repo).context(format!("Failed to fetch {}", dep.name()?))?;
+    }
+
+    repo.find_commit(oid).context(format!(
+        "Failed to find locked commit {} of {}",
+        commit,
+        dep.name()?
+    ))?;
+    checkout(&repo, oid)
+}
+
+/// Whether the checked out commit of a dependency satisfies the
+/// version, branch, tag or revision `dep` requires. A dependency
+/// without any of them is satisfied by every commit
+pub(super) fn is_satisfied(dep: &Dependency) -> anyhow::Result<bool> {
+    let repo = Repository::open(dep_path(dep)?)
+        .context(format!("Failed to open dependency: {}", dep.name()?))?;
+    let head = find_commit(&repo, "HEAD")?;
+    let points_to_head = |spec: &str| find_commit(&repo, spec).ok() == Some(head);
+    match (&dep.version, &dep.git_ref) {
+        (Some(req), _) => Ok(repo.tag_names(None)?.iter().flatten().any(|tag| {
+            parse_tag(tag).is_some_and(|version| req.matches(&version))
+                && points_to_head(&format!("refs/tags/{}", tag))
+        })),
+        (None, Some(GitRef::Tag(tag))) => Ok(points_to_head(&format!("refs/tags/{}", tag))),
+        (None, Some(GitRef::Branch(branch))) => {
+            Ok(points_to_head(&format!("refs/remotes/origin/{}", branch)))
+        }
+        (None, Some(GitRef::Rev(rev))) => Ok(points_to_head(rev)),
+        (None, None) => Ok(true),
+    }
+}
+
+/// Fetch all branches and tags of the dependency
+fn fetch(repo: &Repository) -> anyhow::Result<()> {
+    let mut remote = repo
+        .find_remote("origin")
+        .context("Failed to find remote `origin` of dependency")?;
+    remote
+        .fetch(
+            &[
+                "+refs/heads/*:refs/remotes/origin/*",
+                "+refs/tags/*:refs/tags/*",
+            ],
+            None,
+            None,
+        )
+        .map_err(anyhow::Error::from)
+}
+
+/// Find the commit a branch, tag or revision points to
+fn find_commit(repo: &Repository, spec: &str) -> anyhow::Result<Oid> {
+    repo.revparse_single(spec)
+        .and_then(|obj| obj.peel_to_commit())
+        .map(|commit| commit.id())
+        .context(format!("Failed to find `{}`", spec))
+}
+
+fn checkout(repo: &Repository, oid: Oid) -> anyhow::Result<()> {
+    let commit = repo.find_commit(oid)?;
+    repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().force()))
+        .context(format!("Failed to check out commit: {}", oid))?;
+    repo.set_head_detached(oid)
+        .context(format!("Failed to check out commit: {}", oid))
+}
+
+/// Parse a git tag as a version.
+/// A leading `v` and missing minor and
+/// patch versions are allowed (`v1.2` is `1.2.0`)
+fn parse_tag(tag: &str) -> Option<Version> {
+    let tag = tag.strip_prefix('v').unwrap_or(tag);
+    let (core, rest) = match tag.find(['-', '+']) {
+        Some(pos) => tag.split_at(pos),
+        None => (tag, ""),
+    };
+    let core = match core.split('.').count() {
+        1 => format!("{}.0.0", core),
+        2 => format!("{}.0", core),
+        _ => core.to_string(),
+    };
+    Version::parse(&format!("{}{}", core, rest)).ok()
+}
+
+/// Find the tag with the highest version matching the requirement
+fn resolve_version(repo: &Repository, req: &VersionReq) -> anyhow::Result<String> {
+    let tags = repo.tag_names(None)?;
+    let versions: Vec<(Version, &str)> = tags
+        .iter()
+        .flatten()
+        .filter_map(|tag| parse_tag(tag).map(|version| (version, tag)))
+        .collect();
+
+    match versions
+        .iter()
+        .filter(|(version, _)| req.matches(version))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+    {
+        Some((_, tag)) => Ok(tag.to_string()),
+        None => {
+            let available: Vec<String> = versions.iter().map(|(v, _)| v.to_string()).collect();
+            bail!(
+                "No version matches `{}`. Available versions: {}",
+                req,
+                if available.is_empty() {
+                    "none".into()
+                } else {
+                    available.join(", ")
+                }
+            )
+        }
+    }
 }
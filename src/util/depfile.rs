@@ -0,0 +1,152 @@
+//! Parsing of the make-style dependency files
+//! gcc writes when compiling with `-MMD -MF <file>`.
+//! They are used to decide whether an object file
+//! needs to be rebuilt.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Parse the prerequisites of a depfile.
+/// The target before the `:` is skipped, line
+/// continuations and escaped spaces are handled.
+pub fn parse(content: &str) -> Vec<PathBuf> {
+    let content = content.replace("\\\r\n", " ").replace("\\\n", " ");
+    let prereqs = match content.find(": ") {
+        Some(pos) => &content[pos + 2..],
+        None => return Vec::new(),
+    };
+
+    let mut deps = Vec::new();
+    let mut cur = String::new();
+    let mut chars = prereqs.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&' ') => {
+                cur.push(' ');
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                if !cur.is_empty() {
+                    deps.push(PathBuf::from(&cur));
+                    cur.clear();
+                }
+            }
+            c => cur.push(c),
+        }
+    }
+    if !cur.is_empty() {
+        deps.push(PathBuf::from(cur));
+    }
+    deps
+}
+
+#[inline(always)]
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Whether the object file has to be rebuilt.
+/// This is the case if the object or its depfile
+/// don't exist, or if the source or any header
+/// it includes is newer than the object.
+pub fn is_stale(src: &Path, obj: &Path, depfile: &Path) -> bool {
+    let obj_time = match modified(obj) {
+        Some(time) => time,
+        None => return true,
+    };
+    let content = match fs::read_to_string(depfile) {
+        Ok(content) => content,
+        Err(_) => return true,
+    };
+
+    let mut deps = parse(&content);
+    if !deps.iter().any(|dep| dep == src) {
+        deps.push(src.to_path_buf());
+    }
+
+    deps.iter().any(|dep| match modified(dep) {
+        Some(time) => time > obj_time,
+        None => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs::File, process, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn parse_continuations() {
+        let deps = parse("build/obj/main.o: src/main.c \\\n  include/a.h \\\r\n include/b.h\n");
+        assert_eq!(
+            deps,
+            ["src/main.c", "include/a.h", "include/b.h"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn parse_escaped_spaces() {
+        let deps = parse("main.o: my\\ project/main.c my\\ project/a\\ b.h\n");
+        assert_eq!(
+            deps,
+            ["my project/main.c", "my project/a b.h"].map(PathBuf::from)
+        );
+        assert!(parse("no target").is_empty());
+    }
+
+    /// Write the files of a compiled source to a new directory. The source
+    /// was modified 100 seconds ago, the header and object the given seconds ago
+    fn build(test: &str, header_age: u64, obj_age: u64) -> (PathBuf, PathBuf, PathBuf, PathBuf) {
+        let dir = env::temp_dir().join(format!("surtur-depfile-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (src, header_path) = (dir.join("main.c"), dir.join("main.h"));
+        let (obj, dep) = (dir.join("main.o"), dir.join("main.d"));
+        let now = SystemTime::now();
+        for (path, age) in [(&src, 100), (&header_path, header_age), (&obj, obj_age)] {
+            let file = File::create(path).unwrap();
+            file.set_modified(now - Duration::from_secs(age)).unwrap();
+        }
+        fs::write(
+            &dep,
+            format!(
+                "{}: {} \\\n {}\n",
+                obj.display(),
+                src.display(),
+                header_path.display()
+            ),
+        )
+        .unwrap();
+        (dir, src, obj, dep)
+    }
+
+    #[test]
+    fn up_to_date() {
+        let (dir, src, obj, dep) = build("fresh", 100, 10);
+        assert!(!is_stale(&src, &obj, &dep));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn header_newer_than_object() {
+        let (dir, src, obj, dep) = build("header", 5, 10);
+        assert!(is_stale(&src, &obj, &dep));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_files() {
+        let (dir, src, obj, dep) = build("missing", 100, 10);
+        fs::remove_file(dir.join("main.h")).unwrap();
+        assert!(is_stale(&src, &obj, &dep));
+        fs::remove_file(&dep).unwrap();
+        assert!(is_stale(&src, &obj, &dep));
+        fs::remove_file(&obj).unwrap();
+        assert!(is_stale(&src, &obj, &dep));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+//! A small job pool that limits how many
+//! compiler processes run at the same time.
+//! The pool is shared between all threads of a
+//! build, so compiling dependencies in parallel
+//! never exceeds the requested number of jobs.
+
+use std::{
+    num::NonZeroUsize,
+    sync::{Condvar, Mutex},
+    thread,
+};
+
+pub struct JobPool {
+    jobs: usize,
+    free: Mutex<usize>,
+    cvar: Condvar,
+}
+
+/// A slot in the job pool.
+/// The slot is given back when this is dropped
+pub struct JobToken<'p> {
+    pool: &'p JobPool,
+}
+
+impl JobPool {
+    pub fn new(jobs: usize) -> Self {
+        let jobs = jobs.max(1);
+        Self {
+            jobs,
+            free: Mutex::new(jobs),
+            cvar: Condvar::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn jobs(&self) -> usize {
+        self.jobs
+    }
+
+    /// Block until a slot is free and take it
+    pub fn acquire(&self) -> JobToken<'_> {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.cvar.wait(free).unwrap();
+        }
+        *free -= 1;
+        JobToken { pool: self }
+    }
+}
+
+impl Drop for JobToken<'_> {
+    fn drop(&mut self) {
+        *self.pool.free.lock().unwrap() += 1;
+        self.pool.cvar.notify_one();
+    }
+}
+
+/// Number of jobs used when `--jobs` is not specified
+pub fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1)
+}
@@ -1,5 +1,7 @@
+pub mod depfile;
 pub mod error;
 pub mod files;
+pub mod jobs;
 /// Provides various utility functions
 pub mod macros;
 
@@ -75,8 +77,84 @@ pub fn get_src_files(path: &PathBuf) -> HashSet<PathBuf> {
 }
 
 pub fn result_to_option<T, E: Error>(res: Result<T, E>) -> Option<T> {
-    match res {
-        Ok(val) => Some(val),
-        Err(_) => None,
+    res.ok()
+}
+
+/// Quote and escape a string so it can be used in JSON
+pub fn json_str(val: &str) -> String {
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('"');
+    for c in val.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Quote and escape a string so it can be used as an XML attribute
+pub fn xml_str(val: &str) -> String {
+    format!("\"{}\"", xml_text(val).replace('"', "&quot;"))
+}
+
+/// Escape a string so it can be used as XML text.
+/// Control characters aren't allowed in XML, so they are dropped
+pub fn xml_text(val: &str) -> String {
+    let mut out = String::with_capacity(val.len());
+    for c in val.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\n' | '\r' | '\t' => out.push(c),
+            c if (c as u32) < 0x20 => (),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Quote and escape a string so it can be used in lua
+pub fn lua_str(val: &str) -> String {
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('"');
+    for c in val.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_json() {
+        assert_eq!(json_str("plain"), "\"plain\"");
+        assert_eq!(json_str("say \"hi\" \\ <&>"), r#""say \"hi\" \\ <&>""#);
+        assert_eq!(json_str("a\nb\r\tc"), r#""a\nb\r\tc""#);
+        assert_eq!(json_str("\x01\x1b[31m"), r#""\u0001\u001b[31m""#);
+    }
+
+    #[test]
+    fn escape_xml() {
+        assert_eq!(xml_text("a < b && c > d"), "a &lt; b &amp;&amp; c &gt; d");
+        assert_eq!(xml_text("say \"hi\"\n\tok"), "say \"hi\"\n\tok");
+        assert_eq!(xml_text("\x01\x1b[31mred\x00"), "[31mred");
+        assert_eq!(xml_str("say \"<hi>\""), "\"say &quot;&lt;hi&gt;&quot;\"");
     }
 }